@@ -0,0 +1,208 @@
+use std::{env, path::PathBuf};
+
+use crate::{config::DEFAULT_PATH, error::Error};
+
+/// Environment variable that sets the config path if `--config` isn't given.
+pub const CONFIG_ENV: &str = "WHOOSH_CONFIG";
+/// How long to wait before the first retry after the main loop fails, if `--retry-ms` isn't given.
+const DEFAULT_RETRY_MS: u64 = 2000;
+/// The longest wait between retries, if `--max-retry-ms` isn't given.
+const DEFAULT_MAX_RETRY_MS: u64 = 60000;
+/// The exit code after giving up because of `--give-up-after` or `--give-up-ms`, so that a
+/// supervisor can tell it apart from a bad config.
+pub const GIVE_UP_EXIT_CODE: i32 = 3;
+
+/// How log lines are written, from `--log-format`.
+#[derive(Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    /// Human-readable lines.
+    Plain,
+    /// One JSON object per line, including the spans each event happened in.
+    Json,
+}
+
+pub struct Args {
+    /// From `--config`, then `WHOOSH_CONFIG`, then the default path.
+    pub config_path: PathBuf,
+    /// Never write to any PWM or enable files.
+    pub dry_run: bool,
+    /// Validate the config and exit instead of controlling fans.
+    pub check: bool,
+    /// Poll once, putting every fan straight at its target, then exit.
+    pub once: bool,
+    /// Write the status as JSON to this file after every poll.
+    pub status_json: Option<PathBuf>,
+    /// Print this curve as CSV and exit instead of controlling fans.
+    pub dump_curve: Option<String>,
+    /// With `--dump-curve`, apply this fan's limits to the curve.
+    pub fan: Option<String>,
+    /// Convert this fancontrol config, print it, and exit instead of controlling fans.
+    pub import_fancontrol: Option<PathBuf>,
+    /// Run the config against this recording, print what the fans would do, and exit instead of
+    /// controlling fans.
+    pub replay: Option<PathBuf>,
+    /// Append every poll's readings and fan targets to this file, in the format `--replay` reads.
+    pub record: Option<PathBuf>,
+    /// How long to wait before retrying after the main loop fails. The wait doubles after each
+    /// failure to start, up to `max_retry_ms`.
+    pub retry_ms: u64,
+    pub max_retry_ms: u64,
+    /// Exit with `GIVE_UP_EXIT_CODE` after the main loop fails this many times in a row without
+    /// completing a poll in between.
+    pub give_up_after: Option<u32>,
+    /// Exit with `GIVE_UP_EXIT_CODE` once the main loop has been failing, without completing a
+    /// poll in between, for this long.
+    pub give_up_ms: Option<u64>,
+    pub log_format: LogFormat,
+    /// Append logs to this file instead of writing them to stderr.
+    pub log_file: Option<PathBuf>,
+}
+
+impl Args {
+    pub fn parse() -> Result<Self, Error> {
+        let mut config_path = None;
+        let mut dry_run = false;
+        let mut check = false;
+        let mut once = false;
+        let mut status_json = None;
+        let mut dump_curve = None;
+        let mut fan = None;
+        let mut import_fancontrol = None;
+        let mut replay = None;
+        let mut record = None;
+        let mut retry_ms = DEFAULT_RETRY_MS;
+        let mut max_retry_ms = DEFAULT_MAX_RETRY_MS;
+        let mut give_up_after = None;
+        let mut give_up_ms = None;
+        let mut log_format = LogFormat::Plain;
+        let mut log_file = None;
+        let mut args = env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-c" | "--config" => {
+                    let path = args
+                        .next()
+                        .ok_or_else(|| Error::InvalidArgs(format!("{} requires a path", arg)))?;
+                    config_path = Some(PathBuf::from(path));
+                }
+                "--dry-run" => dry_run = true,
+                "--check" => check = true,
+                "--once" => once = true,
+                "--status-json" => {
+                    let path = args
+                        .next()
+                        .ok_or_else(|| Error::InvalidArgs(format!("{} requires a path", arg)))?;
+                    status_json = Some(PathBuf::from(path));
+                }
+                "--dump-curve" => {
+                    let name = args
+                        .next()
+                        .ok_or_else(|| Error::InvalidArgs(format!("{} requires a name", arg)))?;
+                    dump_curve = Some(name);
+                }
+                "--fan" => {
+                    let name = args
+                        .next()
+                        .ok_or_else(|| Error::InvalidArgs(format!("{} requires a name", arg)))?;
+                    fan = Some(name);
+                }
+                "--import-fancontrol" => {
+                    let path = args
+                        .next()
+                        .ok_or_else(|| Error::InvalidArgs(format!("{} requires a path", arg)))?;
+                    import_fancontrol = Some(PathBuf::from(path));
+                }
+                "--replay" => {
+                    let path = args
+                        .next()
+                        .ok_or_else(|| Error::InvalidArgs(format!("{} requires a path", arg)))?;
+                    replay = Some(PathBuf::from(path));
+                }
+                "--record" => {
+                    let path = args
+                        .next()
+                        .ok_or_else(|| Error::InvalidArgs(format!("{} requires a path", arg)))?;
+                    record = Some(PathBuf::from(path));
+                }
+                "--retry-ms" => retry_ms = parse_ms(&arg, args.next())?,
+                "--max-retry-ms" => max_retry_ms = parse_ms(&arg, args.next())?,
+                "--give-up-after" => {
+                    let value = args.next().ok_or_else(|| {
+                        Error::InvalidArgs(format!("{} requires a number of failures", arg))
+                    })?;
+                    let failures = value.parse().map_err(|_| {
+                        Error::InvalidArgs(format!(
+                            "{} \"{}\" is not a number of failures",
+                            arg, value
+                        ))
+                    })?;
+                    give_up_after = Some(failures);
+                }
+                "--give-up-ms" => give_up_ms = Some(parse_ms(&arg, args.next())?),
+                "--log-format" => {
+                    log_format = match args.next().as_deref() {
+                        Some("plain") => LogFormat::Plain,
+                        Some("json") => LogFormat::Json,
+                        _ => {
+                            return Err(Error::InvalidArgs(format!(
+                                "{} requires \"plain\" or \"json\"",
+                                arg
+                            )))
+                        }
+                    }
+                }
+                "--log-file" => {
+                    let path = args
+                        .next()
+                        .ok_or_else(|| Error::InvalidArgs(format!("{} requires a path", arg)))?;
+                    log_file = Some(PathBuf::from(path));
+                }
+                _ => {
+                    return Err(Error::InvalidArgs(format!(
+                        "unrecognised argument \"{}\"",
+                        arg
+                    )))
+                }
+            }
+        }
+
+        if fan.is_some() && dump_curve.is_none() {
+            return Err(Error::InvalidArgs("--fan requires --dump-curve".to_owned()));
+        }
+        if retry_ms > max_retry_ms {
+            return Err(Error::InvalidArgs(
+                "--retry-ms can't be more than --max-retry-ms".to_owned(),
+            ));
+        }
+
+        Ok(Self {
+            config_path: config_path
+                .or_else(|| env::var_os(CONFIG_ENV).map(PathBuf::from))
+                .unwrap_or_else(|| PathBuf::from(DEFAULT_PATH)),
+            dry_run,
+            check,
+            once,
+            status_json,
+            dump_curve,
+            fan,
+            import_fancontrol,
+            replay,
+            record,
+            retry_ms,
+            max_retry_ms,
+            give_up_after,
+            give_up_ms,
+            log_format,
+            log_file,
+        })
+    }
+}
+
+/// Parses the value of `arg`, a number of milliseconds.
+fn parse_ms(arg: &str, value: Option<String>) -> Result<u64, Error> {
+    let value =
+        value.ok_or_else(|| Error::InvalidArgs(format!("{} requires a number of ms", arg)))?;
+    value
+        .parse()
+        .map_err(|_| Error::InvalidArgs(format!("{} \"{}\" is not a number of ms", arg, value)))
+}
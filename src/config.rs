@@ -1,31 +1,354 @@
 use std::{
-    collections::HashMap,
-    fs::{read_dir, read_to_string, File},
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt::{self, Display, Formatter},
+    fs::{read_dir, File},
     io::Read,
-    path::PathBuf,
+    path::{Path, PathBuf},
+    sync::Arc,
 };
 
-use serde::Deserialize;
-use tracing::{debug, debug_span, trace};
+use serde::{de::Error as _, Deserialize, Deserializer};
+use toml::de::Error as TomlError;
+use tracing::{debug, debug_span, info, trace, warn};
 
-use crate::{error::Error, fan::ControlledFan, Point};
+use crate::{
+    curve::{Curve, Point},
+    error::Error,
+    fan::{ControlledFan, FanOptions},
+    sensor::SensorInput,
+    sysfs::{FakeSysfs, SharedSysfs, Sysfs},
+};
+
+pub const DEFAULT_PATH: &str = "/etc/whoosh.toml";
+/// What the tachometers in `Config::fake_sysfs` read, high enough that fans never look stalled.
+const FAKE_RPM: u32 = 1000;
 
 #[derive(Deserialize)]
 pub struct Config {
+    /// How often to poll, in milliseconds. Defaults to 1000.
+    #[serde(default = "default_poll_period")]
     pub poll_period: u64,
+    /// Changes in speed no bigger than this percentage are ignored. Defaults to 0.
+    #[serde(default = "default_min_change")]
     pub min_change: usize,
+    /// Changes in speed are capped to this percentage per poll. Defaults to 100, i.e. no cap.
+    #[serde(default = "default_max_change")]
     pub max_change: usize,
-    sensors: HashMap<String, Sensor>,
+    pub sensors: HashMap<String, Sensor>,
     pub composites: HashMap<String, Composite>,
-    curves: HashMap<String, Vec<String>>,
+    curves: HashMap<String, CurveSpec>,
     pub fans: HashMap<String, Fan>,
+    /// If set, serve prometheus metrics over HTTP on this address.
+    pub metrics_addr: Option<String>,
+    /// If set, reply to connections on this unix socket with the current status.
+    pub status_socket: Option<PathBuf>,
+    /// The raw PWM value (0-255) every fan is set to when something goes wrong.
+    #[serde(default = "default_failsafe_pwm")]
+    pub failsafe_pwm: u8,
+    /// If set, a failure to read a sensor (without a fallback) or to control a fan stops the
+    /// control loop, rather than just skipping that sensor or fan for the poll.
+    #[serde(default)]
+    pub strict: bool,
+    /// How long to wait, in milliseconds, for hwmons the config refers to to appear before giving
+    /// up on them. Useful when starting before every driver has loaded.
+    #[serde(default)]
+    pub startup_wait_ms: u64,
+    /// If set, hwmons are rescanned every poll, and the config is reloaded when they change.
+    #[serde(default)]
+    pub rescan_hwmons: bool,
+    /// If set, sensors are read concurrently on a few threads, so that one slow sensor doesn't
+    /// hold up the others.
+    #[serde(default)]
+    pub parallel_reads: bool,
+    /// A program and its arguments, run whenever a fan's input rises above its `critical_temp`.
+    /// `WHOOSH_FAN` and `WHOOSH_TEMP` (in millidegrees celsius) are set in its environment.
+    pub critical_command: Option<Vec<String>>,
+    /// What happens to each fan when whoosh lets go of it.
+    #[serde(default)]
+    pub shutdown_mode: ShutdownMode,
+    /// If set, hwmon names and sensor labels are matched case-insensitively.
+    #[serde(default)]
+    pub ignore_case: bool,
+    /// Alternative curves and limits that can be switched to at runtime.
+    #[serde(default)]
+    pub profiles: HashMap<String, Profile>,
+    /// Profiles to switch to at certain times of day. The first entry covering the current time
+    /// wins, and outside every entry the base config is used.
+    #[serde(default)]
+    pub schedule: Vec<ScheduleEntry>,
+    /// If set, a single line summarising every fan is logged at info level after each poll.
+    #[serde(default)]
+    pub log_summary: bool,
+    /// How many polls after startup each fan takes to ramp from its speed at startup to its
+    /// target, instead of jumping there at once. 0 disables this.
+    #[serde(default)]
+    pub soft_start_polls: u32,
+    /// How temperatures are shown in logs and on the status socket. The status JSON and metrics
+    /// keep their own units regardless.
+    #[serde(default)]
+    pub display_unit: TempUnit,
+    /// If set, a fan's speed is only written when it differs from what was last written to it,
+    /// even if the fan reads back as something else. Saves writes on hardware that rounds speeds,
+    /// but a speed changed by something other than whoosh is only corrected once the target
+    /// changes.
+    #[serde(default)]
+    pub coalesce_writes: bool,
 }
 
+/// Activates `profile` every day from `start` until `end`, which are local times like `22:00`. If
+/// `end` is earlier than `start`, the entry runs past midnight, and if they're equal, it covers
+/// the whole day.
+#[derive(Deserialize)]
+pub struct ScheduleEntry {
+    #[serde(deserialize_with = "deserialize_time")]
+    start: u32,
+    #[serde(deserialize_with = "deserialize_time")]
+    end: u32,
+    /// The profile to activate, where `default` means the base config.
+    pub profile: String,
+}
+
+impl ScheduleEntry {
+    /// Whether this entry covers `minute` minutes after midnight.
+    fn covers(&self, minute: u32) -> bool {
+        if self.start < self.end {
+            minute >= self.start && minute < self.end
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+/// Parses a time like `22:00` into minutes after midnight.
+fn deserialize_time<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+    let time = String::deserialize(deserializer)?;
+    time.split_once(':')
+        .and_then(|(hours, minutes)| {
+            Some((hours.parse::<u32>().ok()?, minutes.parse::<u32>().ok()?))
+        })
+        .filter(|&(hours, minutes)| hours < 24 && minutes < 60)
+        .map(|(hours, minutes)| hours * 60 + minutes)
+        .ok_or_else(|| D::Error::custom(format!("invalid time {:?}, expected HH:MM", time)))
+}
+
+/// Overrides applied on top of the rest of the config while a profile is active.
+#[derive(Deserialize)]
+pub struct Profile {
+    /// Replaces the curves of the same name in `[curves]`.
+    #[serde(default)]
+    curves: HashMap<String, CurveSpec>,
+    /// Replaces the global `min_change`, but not per-fan overrides.
+    pub min_change: Option<usize>,
+    /// Replaces the global `max_change`, but not per-fan overrides.
+    pub max_change: Option<usize>,
+}
+
+// these log, so that it's clear what a minimal config actually does
+
+fn default_poll_period() -> u64 {
+    info!("poll_period not set - defaulting to 1000ms");
+    1000
+}
+
+fn default_min_change() -> usize {
+    info!("min_change not set - defaulting to 0%");
+    0
+}
+
+fn default_max_change() -> usize {
+    info!("max_change not set - defaulting to 100%");
+    100
+}
+
+fn default_failsafe_pwm() -> u8 {
+    255
+}
+
+/// What to leave a fan doing once whoosh stops controlling it.
+#[derive(Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ShutdownMode {
+    /// Restore whatever enable mode the fan had when whoosh took control of it.
+    #[default]
+    Restore,
+    /// Hand the fan over to automatic control (enable mode 2).
+    Auto,
+    /// Leave the fan under manual control at `failsafe_pwm`.
+    Failsafe,
+}
+
+/// A unit to show temperatures in. Everything is still worked out in millidegrees celsius.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TempUnit {
+    /// Millidegrees celsius, as linux reports them.
+    #[default]
+    Millidegrees,
+    /// Degrees celsius, to one decimal place.
+    Celsius,
+    /// Degrees fahrenheit, to one decimal place.
+    Fahrenheit,
+}
+
+impl TempUnit {
+    /// Wraps `temp`, in millidegrees celsius, so that it is displayed in this unit.
+    pub fn show(self, temp: i32) -> ShownTemp {
+        ShownTemp { temp, unit: self }
+    }
+}
+
+/// A temperature that displays itself in a particular unit, from `TempUnit::show`.
+#[derive(Clone, Copy)]
+pub struct ShownTemp {
+    temp: i32,
+    unit: TempUnit,
+}
+
+impl Display for ShownTemp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let celsius = self.temp as f64 / 1000.0;
+        match self.unit {
+            TempUnit::Millidegrees => write!(f, "{}", self.temp),
+            TempUnit::Celsius => write!(f, "{:.1}C", celsius),
+            TempUnit::Fahrenheit => write!(f, "{:.1}F", celsius * 9.0 / 5.0 + 32.0),
+        }
+    }
+}
+
+// so that optional and collected temperatures can be logged with `?`
+impl fmt::Debug for ShownTemp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Sensor {
+    #[serde(flatten)]
+    source: SensorSource,
+    /// If set, readings are smoothed with an exponential moving average, where this (between 0
+    /// and 1) is the weight given to each new reading.
+    pub smoothing: Option<f64>,
+    /// If set, the value used is the mean of this many of the most recent readings.
+    pub window: Option<usize>,
+    /// Readings are multiplied by this, then `offset` is added, before anything else happens to
+    /// them.
+    #[serde(default = "default_scale")]
+    scale: f64,
+    /// Added to readings after `scale`. Like every temperature in the config, this is in
+    /// millidegrees celsius if it's a number, or can be a string like `"2C"` or `"3.6F"`.
+    #[serde(default, deserialize_with = "deserialize_temp_difference")]
+    offset: i32,
+    /// Calibrated readings below this are discarded as bogus.
+    #[serde(default, deserialize_with = "deserialize_optional_temp")]
+    min_valid: Option<i32>,
+    /// Calibrated readings above this are discarded as bogus.
+    #[serde(default, deserialize_with = "deserialize_optional_temp")]
+    max_valid: Option<i32>,
+    /// Another sensor whose value is used when this one can't be read or is implausible.
+    pub fallback: Option<String>,
+}
+
+fn default_scale() -> f64 {
+    1.0
+}
+
+impl Sensor {
+    /// Applies the sensor's `scale` and `offset` to a raw reading.
+    pub fn calibrate(&self, raw_temp: i32) -> i32 {
+        // the cast saturates too, so a reading near the limits can't overflow either way
+        ((raw_temp as f64 * self.scale).round() as i32).saturating_add(self.offset)
+    }
+
+    /// Whether a calibrated reading is within the sensor's `min_valid` and `max_valid`.
+    pub fn is_plausible(&self, temp: i32) -> bool {
+        self.min_valid.is_none_or(|min| temp >= min) && self.max_valid.is_none_or(|max| temp <= max)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SensorSource {
+    ByLabel {
+        #[serde(flatten)]
+        hwmon: HwmonId,
+        label: String,
+    },
+    ByIndex {
+        #[serde(flatten)]
+        hwmon: HwmonId,
+        index: usize,
+    },
+    /// Any file containing a temperature. Use `scale` if it isn't in millidegrees celsius.
+    File { path: PathBuf },
+    /// A `/sys/class/thermal/thermal_zoneN`, identified by its `type`.
+    ThermalZone { thermal_zone: String },
+    /// The index of an NVIDIA GPU. Only available with the `nvml` feature.
+    Nvml {
+        #[cfg_attr(not(feature = "nvml"), allow(dead_code))]
+        nvml_gpu: u32,
+    },
+}
+
+impl SensorSource {
+    /// The hwmon this sensor belongs to, if it belongs to one.
+    fn hwmon(&self) -> Option<&HwmonId> {
+        match self {
+            SensorSource::ByLabel { hwmon, .. } => Some(hwmon),
+            SensorSource::ByIndex { hwmon, .. } => Some(hwmon),
+            SensorSource::File { .. }
+            | SensorSource::ThermalZone { .. }
+            | SensorSource::Nvml { .. } => None,
+        }
+    }
+}
+
+/// The sensor indices that labels were found at, carried between loads so that reloading doesn't
+/// mean rereading every label.
+#[derive(Default)]
+pub struct LabelCache {
+    /// The hwmons the cache was filled from.
+    hwmon_names: BTreeMap<usize, String>,
+    /// Maps hwmon index, label and `ignore_case` to the index of the sensor.
+    indices: HashMap<(usize, String, bool), usize>,
+}
+
+impl LabelCache {
+    pub fn invalidate(&mut self) {
+        self.indices.clear();
+    }
+}
+
+/// How to find an hwmon.
 #[derive(Deserialize)]
 #[serde(untagged)]
-pub enum Sensor {
-    ByNameLabel { hwmon_name: String, label: String },
-    ByNameIndex { hwmon_name: String, index: usize },
+enum HwmonId {
+    /// By its `name`, which isn't necessarily unique. If several hwmons have the same name,
+    /// `instance` picks one of them, counting from 0 in order of their `hwmonN` index.
+    Name {
+        hwmon_name: String,
+        instance: Option<usize>,
+    },
+    /// By where its `device` symlink points, which doesn't change between boots. This can be the
+    /// full path under `/sys/devices`, or just the last part of it, e.g. a PCI address like
+    /// `0000:03:00.0`.
+    Device { hwmon_device: String },
+}
+
+impl Display for HwmonId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            HwmonId::Name {
+                hwmon_name,
+                instance: None,
+            } => write!(f, "{}", hwmon_name),
+            HwmonId::Name {
+                hwmon_name,
+                instance: Some(instance),
+            } => write!(f, "{} (instance {})", hwmon_name, instance),
+            HwmonId::Device { hwmon_device } => write!(f, "device {}", hwmon_device),
+        }
+    }
 }
 
 #[derive(Deserialize)]
@@ -41,170 +364,1409 @@ pub struct Composite {
 pub enum CompositeMode {
     Mean,
     Max,
-    MeanMax { threshold: i32 },
+    Min,
+    /// The mean of the inputs, unless any input exceeds `threshold`, in which case the max.
+    MeanMax {
+        #[serde(deserialize_with = "deserialize_temp")]
+        threshold: i32,
+    },
+    /// The mean of the inputs, each weighted by the entry at the same position in `weights`. If
+    /// `weights` is omitted, every input has a weight of 1.0.
+    WeightedMean {
+        #[serde(default)]
+        weights: Vec<f32>,
+    },
+}
+
+impl Composite {
+    /// The weight of the input at position `index`.
+    pub fn weight(&self, index: usize) -> f32 {
+        match &self.mode {
+            CompositeMode::WeightedMean { weights } => weights.get(index).copied().unwrap_or(1.0),
+            _ => 1.0,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum CurveSpec {
+    Points(Vec<String>),
+    Detailed {
+        points: Vec<String>,
+        #[serde(default)]
+        mode: CurveMode,
+    },
+}
+
+impl CurveSpec {
+    fn points(&self) -> &[String] {
+        match self {
+            CurveSpec::Points(points) => points,
+            CurveSpec::Detailed { points, .. } => points,
+        }
+    }
+
+    fn mode(&self) -> CurveMode {
+        match self {
+            CurveSpec::Points(_) => CurveMode::default(),
+            CurveSpec::Detailed { mode, .. } => *mode,
+        }
+    }
+}
+
+/// How fan speeds are calculated between the points of a curve.
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CurveMode {
+    /// Straight lines between points.
+    #[default]
+    Linear,
+    /// Monotone cubic interpolation, which has no corners at the points.
+    Smooth,
+    /// No interpolation - each point's speed is used until the next point is reached.
+    Step,
 }
 
 #[derive(Deserialize)]
 pub struct Fan {
     path: FanPath,
-    pub input: String,
+    /// The sensor or composite this fan follows. If several are given, the hottest is used.
+    input: FanInput,
+    /// How the fan's speed is calculated from its input.
+    #[serde(default)]
+    pub mode: FanMode,
+    /// Required in curve mode.
+    pub curve: Option<FanCurve>,
+    /// The speed from the curve is multiplied by this, then `curve_offset` is added, so that fans
+    /// can share a curve but run at different speeds.
+    #[serde(default = "default_scale")]
+    curve_scale: f64,
+    /// Added to the speed from the curve after `curve_scale`, in percent.
+    #[serde(default)]
+    curve_offset: f64,
+    /// Required in pid mode.
+    pub pid: Option<PidParams>,
+    /// Required in blend mode.
+    pub blend: Option<BlendParams>,
+    /// How far the input temperature must fall before the fan slows down.
+    #[serde(default, deserialize_with = "deserialize_temp_difference")]
+    pub hysteresis: i32,
+    /// The lowest speed the fan may be set to, in percent, except that it is still stopped below
+    /// `stop_below`.
+    min_pwm: Option<u8>,
+    /// The highest speed the fan may be set to, in percent.
+    max_pwm: Option<u8>,
+    /// If set, the fan is never set slower than it was when whoosh took control of it, unless it
+    /// is below `stop_below`.
+    #[serde(default)]
+    pub initial_floor: bool,
+    /// Corrects for a fan whose airflow isn't proportional to its PWM value, by raising the
+    /// fraction of full speed given by the curve to this power. Values below 1 speed up the low
+    /// end. Applied before `min_pwm` and `max_pwm`.
+    gamma: Option<f64>,
+    /// Below this temperature, the fan is stopped regardless of its curve or `min_pwm`. At exactly
+    /// this temperature, it goes straight to at least `min_pwm`.
+    #[serde(default, deserialize_with = "deserialize_optional_temp")]
+    stop_below: Option<i32>,
+    /// Above this temperature, the fan is set straight to full speed regardless of its curve or
+    /// ramp limits.
+    #[serde(default, deserialize_with = "deserialize_optional_temp")]
+    critical_temp: Option<i32>,
+    /// The raw PWM value (0-255) written briefly when starting a stopped fan, so that it actually
+    /// starts spinning.
+    pub spinup_pwm: Option<u8>,
+    /// How long to hold `spinup_pwm` for, in milliseconds.
+    #[serde(default = "default_spinup_ms")]
+    pub spinup_ms: u64,
+    /// If the fan is set above this raw PWM value (0-255) but its tachometer keeps reading 0 RPM,
+    /// it is considered stalled. Requires `path.tach_index`.
+    pub stall_pwm: Option<u8>,
+    /// Whether to set a stalled fan to full speed to try to restart it.
+    #[serde(default)]
+    pub stall_restart: bool,
+    /// After this many polls in a row fail to set the fan's speed, stop trying until the config
+    /// is reloaded. Other fans carry on either way.
+    pub max_write_errors: Option<u32>,
+    /// The value written to `pwmN_enable` to take manual control of the fan.
+    #[serde(default = "default_enable_mode")]
+    enable_mode: u8,
+    /// Whether to drive the fan through `pwmN` alone if it has no `pwmN_enable`, as on some
+    /// controllers that have no enable per channel. When whoosh lets go of such a fan, `restore`
+    /// puts back the speed it had at startup, and `auto` acts like `failsafe`.
+    #[serde(default)]
+    optional_enable: bool,
+    /// Whether a higher PWM value makes this fan slower, e.g. because of how it's wired.
+    #[serde(default)]
+    invert: bool,
+    /// Decreases in speed are held off until this many milliseconds after the fan last needed to
+    /// be at least as fast as it is.
+    pub rampdown_delay_ms: Option<u64>,
+    /// After the fan's speed is changed, it isn't changed again for this many milliseconds,
+    /// unless its input goes above `critical_temp`.
+    pub min_dwell_ms: Option<u64>,
+    /// Overrides the global `min_change` for this fan.
+    pub min_change: Option<usize>,
+    /// Overrides the global `max_change` for this fan.
+    pub max_change: Option<usize>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum FanInput {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FanMode {
+    /// Looks the speed up on `curve`.
+    #[default]
+    Curve,
+    /// Adjusts the speed to hold the input at `pid.target_temp`.
+    Pid,
+    /// Combines the speeds from several curves, each following its own input. The fan's own
+    /// `input` is still what `hysteresis`, `stop_below` and `critical_temp` apply to.
+    Blend,
+}
+
+/// Gains for a fan in pid mode. The controller's output is a raw PWM value (0-255).
+#[derive(Deserialize)]
+pub struct PidParams {
+    /// The temperature to hold the input at.
+    #[serde(deserialize_with = "deserialize_temp")]
+    pub target_temp: i32,
+    /// PWM per degree above the target.
+    #[serde(default)]
+    pub kp: f64,
+    /// PWM per degree-second spent above the target.
+    #[serde(default)]
+    pub ki: f64,
+    /// PWM per degree per second that the input is rising.
+    #[serde(default)]
+    pub kd: f64,
+}
+
+/// The curves of a fan in blend mode.
+#[derive(Deserialize)]
+pub struct BlendParams {
+    /// How the speeds from each source are combined.
+    #[serde(default)]
+    pub combine: BlendCombine,
+    pub sources: Vec<BlendSource>,
+}
+
+#[derive(Deserialize)]
+pub struct BlendSource {
+    /// The name of a curve in `[curves]`.
     pub curve: String,
+    /// The sensor or composite the curve follows.
+    pub input: String,
+    #[serde(default = "default_weight")]
+    pub weight: f32,
+}
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BlendCombine {
+    /// The highest of the speeds, each first multiplied by its weight.
+    #[default]
+    WeightedMax,
+    /// The mean of the speeds, weighted by their weights.
+    WeightedMean,
+}
+
+fn default_weight() -> f32 {
+    1.0
+}
+
+/// Either the name of a curve in `[curves]`, or a curve defined in place.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum FanCurve {
+    Named(String),
+    Inline(CurveSpec),
+}
+
+fn default_enable_mode() -> u8 {
+    1
+}
+
+fn default_spinup_ms() -> u64 {
+    1000
+}
+
+impl Fan {
+    /// The names of the sensors and composites this fan follows.
+    pub fn inputs(&self) -> &[String] {
+        match &self.input {
+            FanInput::Single(input) => std::slice::from_ref(input),
+            FanInput::Multiple(inputs) => inputs,
+        }
+    }
+
+    /// Whether the fan should be stopped at `temp` millidegrees.
+    pub fn should_stop(&self, temp: i32) -> bool {
+        self.stop_below.is_some_and(|stop_below| temp < stop_below)
+    }
+
+    /// Whether `temp` millidegrees is above this fan's critical temperature.
+    pub fn is_critical(&self, temp: i32) -> bool {
+        self.critical_temp
+            .is_some_and(|critical_temp| temp > critical_temp)
+    }
+
+    /// Applies this fan's `curve_scale` and `curve_offset` to `speed` from its curve.
+    pub fn adjust_curve_speed(&self, speed: u8) -> u8 {
+        let speed = speed as f64 * self.curve_scale + self.curve_offset * 255.0 / 100.0;
+        speed.round().clamp(0.0, 255.0) as u8
+    }
+
+    /// Remaps `speed` by this fan's `gamma`, if it has one.
+    pub fn apply_gamma(&self, speed: u8) -> u8 {
+        match self.gamma {
+            Some(gamma) => ((speed as f64 / 255.0).powf(gamma) * 255.0).round() as u8,
+            None => speed,
+        }
+    }
+
+    /// Limits `speed`, as calculated for `temp` millidegrees, to what the fan may actually run at:
+    /// 0 below `stop_below`, and otherwise between `min_pwm` and `max_pwm`.
+    pub fn limit_speed(&self, temp: i32, speed: u8) -> u8 {
+        if self.should_stop(temp) {
+            0
+        } else {
+            self.clamp_speed(speed)
+        }
+    }
+
+    /// Clamps `speed` to this fan's configured `min_pwm` and `max_pwm`.
+    pub fn clamp_speed(&self, speed: u8) -> u8 {
+        let mut speed = speed;
+        if let Some(max) = self.max_pwm {
+            speed = speed.min(percent_to_pwm(max));
+        }
+        if let Some(min) = self.min_pwm {
+            speed = speed.max(percent_to_pwm(min));
+        }
+        speed
+    }
+}
+
+/// Converts a percentage, which `validate_fans` has checked is at most 100, to the 0-255 range
+/// used by linux.
+fn percent_to_pwm(percent: u8) -> u8 {
+    (percent as u32 * 255 / 100) as u8
 }
 
 #[derive(Deserialize)]
 struct FanPath {
-    hwmon_name: String,
+    #[serde(flatten)]
+    hwmon: HwmonId,
     index: usize,
+    /// The index of the `fanN_input` tachometer belonging to this fan, if it has one.
+    tach_index: Option<usize>,
 }
 
 impl Config {
-    pub(crate) fn load() -> Result<Self, Error> {
-        let mut file = File::open("/etc/whoosh.toml")?;
-        let mut contents = String::new();
-        file.read_to_string(&mut contents)?;
-        let config = toml::from_str(&contents)?;
+    /// Loads the config at `path`, then merges in every `*.toml` file in the drop-in directory
+    /// next to it (`/etc/whoosh.d` for `/etc/whoosh.toml`) in order of filename.
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let contents = read_config(path)?;
+        let drop_in_dir = path.with_extension("d");
+        let mut drop_ins = Vec::new();
+        if drop_in_dir.is_dir() {
+            for entry in read_dir(&drop_in_dir)? {
+                let path = entry?.path();
+                if path
+                    .extension()
+                    .is_some_and(|extension| extension == "toml")
+                {
+                    drop_ins.push(path);
+                }
+            }
+            drop_ins.sort();
+        }
+        let config: Config = if drop_ins.is_empty() {
+            // straight from the text, so that type errors have a line and column too
+            toml::from_str(&contents).map_err(|error| Error::toml(path, error))?
+        } else {
+            let mut merged = parse_toml(path, &contents)?;
+            let mut files = vec![(path.to_owned(), contents)];
+            for drop_in in drop_ins {
+                debug!(path = ?drop_in, "merging drop-in config");
+                let contents = read_config(&drop_in)?;
+                merge_toml(&mut merged, parse_toml(&drop_in, &contents)?);
+                files.push((drop_in, contents));
+            }
+            toml::Value::Table(merged)
+                .try_into()
+                .map_err(|error| locate_error(&files, error))?
+        };
+        config.validate_sensors()?;
+        config.validate_composites()?;
+        config.validate_fans()?;
         Ok(config)
     }
 
-    pub(crate) fn parse_curves(&self) -> Result<HashMap<String, Vec<Point>>, Error> {
-        let span = debug_span!("parsing curves");
+    fn validate_sensors(&self) -> Result<(), Error> {
+        for (name, sensor) in self.sensors.iter() {
+            if let Some(alpha) = sensor.smoothing {
+                if !(alpha > 0.0 && alpha <= 1.0) {
+                    return Err(Error::InvalidSmoothing(name.clone()));
+                }
+            }
+            if sensor.window == Some(0) {
+                return Err(Error::InvalidWindow(name.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_composites(&self) -> Result<(), Error> {
+        for (name, composite) in self.composites.iter() {
+            if let CompositeMode::WeightedMean { weights } = &composite.mode {
+                // no weights means every input has a weight of 1
+                if !weights.is_empty() && !weights_are_valid(weights.iter().copied()) {
+                    return Err(Error::InvalidWeight {
+                        kind: "Composite",
+                        name: name.clone(),
+                    });
+                }
+                if !weights.is_empty() && weights.len() != composite.inputs.len() {
+                    return Err(Error::WeightCountMismatch {
+                        composite: name.clone(),
+                        weights: weights.len(),
+                        inputs: composite.inputs.len(),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_fans(&self) -> Result<(), Error> {
+        for (name, fan) in self.fans.iter() {
+            match fan.mode {
+                FanMode::Curve if fan.curve.is_none() => {
+                    return Err(Error::MissingFanCurve(name.clone()))
+                }
+                FanMode::Pid if fan.pid.is_none() => return Err(Error::MissingPid(name.clone())),
+                FanMode::Blend => match &fan.blend {
+                    Some(blend) if !blend.sources.is_empty() => {
+                        if !weights_are_valid(blend.sources.iter().map(|source| source.weight)) {
+                            return Err(Error::InvalidWeight {
+                                kind: "Fan",
+                                name: name.clone(),
+                            });
+                        }
+                    }
+                    _ => return Err(Error::MissingBlend(name.clone())),
+                },
+                _ => {}
+            }
+            if let Some(percent) = [fan.min_pwm, fan.max_pwm]
+                .iter()
+                .flatten()
+                .copied()
+                .find(|&percent| percent > 100)
+            {
+                return Err(Error::SpeedOutOfRange(format!("{}%", percent)).in_fan(name));
+            }
+            if fan
+                .gamma
+                .is_some_and(|gamma| !(gamma.is_finite() && gamma > 0.0))
+            {
+                return Err(Error::InvalidGamma(name.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that every composite input, fan input, and fan curve refers to something that
+    /// exists.
+    pub(crate) fn check_references(&self) -> Result<(), Error> {
+        let input_exists =
+            |name: &String| self.sensors.contains_key(name) || self.composites.contains_key(name);
+        let mut dangling = Vec::new();
+        for (name, sensor) in self.sensors.iter() {
+            match &sensor.fallback {
+                Some(fallback) if !self.sensors.contains_key(fallback) => {
+                    dangling.push(format!("sensor {} has unknown fallback {}", name, fallback));
+                }
+                _ => {}
+            }
+        }
+        for (name, composite) in self.composites.iter() {
+            for input in composite.inputs.iter().filter(|input| !input_exists(input)) {
+                dangling.push(format!("composite {} has unknown input {}", name, input));
+            }
+        }
+        for (name, fan) in self.fans.iter() {
+            for input in fan.inputs().iter().filter(|input| !input_exists(input)) {
+                dangling.push(format!("fan {} has unknown input {}", name, input));
+            }
+            match &fan.curve {
+                Some(FanCurve::Named(curve)) if !self.curves.contains_key(curve) => {
+                    dangling.push(format!("fan {} has unknown curve {}", name, curve));
+                }
+                _ => {}
+            }
+            for source in fan.blend.iter().flat_map(|blend| blend.sources.iter()) {
+                if !input_exists(&source.input) {
+                    dangling.push(format!("fan {} has unknown input {}", name, source.input));
+                }
+                if !self.curves.contains_key(&source.curve) {
+                    dangling.push(format!("fan {} has unknown curve {}", name, source.curve));
+                }
+            }
+        }
+        for entry in self.schedule.iter() {
+            if entry.profile != "default" && !self.profiles.contains_key(&entry.profile) {
+                dangling.push(format!("schedule has unknown profile {}", entry.profile));
+            }
+        }
+        if !dangling.is_empty() {
+            return Err(Error::DanglingReferences(dangling.join(", ")));
+        }
+        Ok(())
+    }
+
+    /// Compares hwmon names or sensor labels, respecting `ignore_case`.
+    fn names_match(&self, a: &str, b: &str) -> bool {
+        if self.ignore_case {
+            a.eq_ignore_ascii_case(b)
+        } else {
+            a == b
+        }
+    }
+
+    /// Finds the index of the hwmon identified by `hwmon`.
+    fn find_hwmon(
+        &self,
+        sysfs: &dyn Sysfs,
+        hwmon_names: &BTreeMap<usize, String>,
+        hwmon: &HwmonId,
+    ) -> Result<usize, Error> {
+        match hwmon {
+            HwmonId::Name {
+                hwmon_name,
+                instance,
+            } => {
+                let mut matches = hwmon_names
+                    .iter()
+                    .filter(|(_i, name)| self.names_match(name, hwmon_name))
+                    .map(|(i, _name)| *i);
+                let found = match instance {
+                    Some(instance) => matches.nth(*instance),
+                    None => {
+                        let first = matches.next();
+                        if matches.next().is_some() {
+                            warn!(
+                                hwmon_name = hwmon_name.as_str(),
+                                "several hwmons have this name - using the first, set instance to choose"
+                            );
+                        }
+                        first
+                    }
+                };
+                found.ok_or_else(|| Error::HwmonNameNotFound(hwmon.to_string()))
+            }
+            HwmonId::Device { hwmon_device } => hwmon_names
+                .keys()
+                .copied()
+                .find(|i| device_matches(sysfs, *i, hwmon_device))
+                .ok_or_else(|| Error::HwmonDeviceNotFound(hwmon_device.to_owned())),
+        }
+    }
+
+    /// The hwmons that sensors or fans refer to, but which aren't in `hwmon_names`.
+    pub(crate) fn missing_hwmons(
+        &self,
+        sysfs: &dyn Sysfs,
+        hwmon_names: &BTreeMap<usize, String>,
+    ) -> Vec<String> {
+        let mut missing: Vec<String> = self
+            .sensors
+            .values()
+            .filter_map(|sensor| sensor.source.hwmon())
+            .chain(self.fans.values().map(|fan| &fan.path.hwmon))
+            .filter(|hwmon| self.find_hwmon(sysfs, hwmon_names, hwmon).is_err())
+            .map(|hwmon| hwmon.to_string())
+            .collect();
+        missing.sort_unstable();
+        missing.dedup();
+        missing
+    }
+
+    /// Orders sensor names so that each comes after its fallback.
+    pub(crate) fn sensor_order(&self) -> Result<Vec<String>, Error> {
+        let mut order = Vec::with_capacity(self.sensors.len());
+        for name in self.sensors.keys() {
+            // walk the fallback chain and add it in reverse, stopping at anything already added
+            let mut chain = Vec::new();
+            let mut next = Some(name);
+            while let Some(name) = next {
+                if order.contains(name) {
+                    break;
+                }
+                if chain.contains(&name) {
+                    return Err(Error::FallbackCycle(name.clone()));
+                }
+                chain.push(name);
+                next = self
+                    .sensors
+                    .get(name)
+                    .and_then(|sensor| sensor.fallback.as_ref())
+                    .filter(|fallback| self.sensors.contains_key(*fallback));
+            }
+            order.extend(chain.into_iter().rev().cloned());
+        }
+        Ok(order)
+    }
+
+    /// Orders composite names so that each comes after any composites it uses as inputs.
+    pub(crate) fn composite_order(&self) -> Result<Vec<String>, Error> {
+        let mut order = Vec::with_capacity(self.composites.len());
+        for name in self.composites.keys() {
+            self.visit_composite(name, &mut Vec::new(), &mut order)?;
+        }
+        Ok(order)
+    }
+
+    /// Depth-first part of `composite_order`. `chain` holds the composites currently being
+    /// visited, to detect cycles.
+    fn visit_composite<'a>(
+        &'a self,
+        name: &'a String,
+        chain: &mut Vec<&'a String>,
+        order: &mut Vec<String>,
+    ) -> Result<(), Error> {
+        if order.contains(name) {
+            return Ok(());
+        }
+        if chain.contains(&name) {
+            return Err(Error::CompositeCycle(name.clone()));
+        }
+        chain.push(name);
+        for input in self.composites[name].inputs.iter() {
+            if self.composites.contains_key(input) {
+                self.visit_composite(input, chain, order)?;
+            }
+        }
+        chain.pop();
+        order.push(name.clone());
+        Ok(())
+    }
+
+    /// The profile scheduled for `minute` minutes after midnight, if any. `None` means no entry
+    /// covers it, and `Some("default")` means the base config.
+    pub(crate) fn scheduled_profile(&self, minute: u32) -> Option<&str> {
+        self.schedule
+            .iter()
+            .find(|entry| entry.covers(minute))
+            .map(|entry| entry.profile.as_str())
+    }
+
+    /// Parses the curves in `[curves]`, with those of `profile` (which must exist) in place of
+    /// any of the same name.
+    pub(crate) fn parse_curves(
+        &self,
+        profile: Option<&str>,
+    ) -> Result<HashMap<String, Curve>, Error> {
+        let span = debug_span!("parsing curves", ?profile);
         let _guard = span.enter();
         let mut ret = HashMap::with_capacity(self.curves.len());
         for (name, curve_spec) in self.curves.iter() {
-            let mut curve = Vec::<Point>::with_capacity(curve_spec.len());
-            for point_spec in curve_spec.iter() {
-                trace!(point_spec = point_spec.as_str(), "parsing point_spec...");
-                let (temp, fan_speed) = {
-                    let mut iter = point_spec.split('/');
-                    let temp: i32 = iter
-                        .next()
-                        .ok_or(Error::InvalidPointSpec)?
-                        .trim_end_matches('C')
-                        .parse()
-                        .map_err(|_| Error::InvalidPointSpec)?;
-                    let fan_percent: i32 = iter
-                        .next()
-                        .ok_or(Error::InvalidPointSpec)?
-                        .trim_end_matches('%')
-                        .parse()
-                        .map_err(|_| Error::InvalidPointSpec)?;
-                    // linux works in milli degrees celsius, and 0-255 fan speed
-                    (temp * 1000, (fan_percent * 255 / 100) as u8)
-                };
-                curve.push(Point { temp, fan_speed });
+            ret.insert(name.clone(), parse_curve(name, curve_spec)?);
+        }
+        if let Some(profile) = profile {
+            for (name, curve_spec) in self.profiles[profile].curves.iter() {
+                ret.insert(name.clone(), parse_curve(name, curve_spec)?);
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Parses the curves defined inline on fans, keyed by the fan's name.
+    pub(crate) fn parse_inline_curves(&self) -> Result<HashMap<String, Curve>, Error> {
+        let span = debug_span!("parsing inline curves");
+        let _guard = span.enter();
+        let mut ret = HashMap::new();
+        for (name, fan) in self.fans.iter() {
+            if let Some(FanCurve::Inline(curve_spec)) = &fan.curve {
+                ret.insert(name.clone(), parse_curve(name, curve_spec)?);
             }
-            ret.insert(name.clone(), curve);
         }
         Ok(ret)
     }
 
+    /// Finds the index of the `tempN_label` in `/sys/class/hwmon/hwmon{hwmon_index}` that reads
+    /// `label`.
+    fn find_label(
+        &self,
+        sysfs: &dyn Sysfs,
+        hwmon_index: usize,
+        label: &str,
+    ) -> Result<usize, Error> {
+        let mut sensor_index = None;
+
+        let dir = format!("/sys/class/hwmon/hwmon{}/", hwmon_index);
+        for entry in sysfs.read_dir(Path::new(&dir))? {
+            let _span = debug_span!("checking entry");
+            let file_name = entry.file_name().unwrap().to_str().unwrap();
+
+            if !file_name.starts_with("temp") || !file_name.ends_with("_label") {
+                continue;
+            }
+            debug!(file_name, "found temp sensor");
+
+            let this_label = sysfs.read_to_string(&entry)?.trim().to_owned();
+            if self.names_match(&this_label, label) {
+                let index: usize = file_name
+                    .trim_start_matches("temp")
+                    .trim_end_matches("_label")
+                    .parse()
+                    .unwrap();
+                sensor_index = Some(index);
+            }
+        }
+
+        sensor_index.ok_or(Error::HwmonSensorNotFound)
+    }
+
+    /// Resolves every sensor to where it can be read from. Sensors found by label are looked up
+    /// in `label_cache` first, which is emptied if the hwmons have changed since it was filled.
     pub(crate) fn find_sensors(
         &self,
-        hwmon_names: &[String],
-    ) -> Result<HashMap<String, PathBuf>, Error> {
+        sysfs: &dyn Sysfs,
+        hwmon_names: &BTreeMap<usize, String>,
+        label_cache: &mut LabelCache,
+    ) -> Result<HashMap<String, SensorInput>, Error> {
         let span = debug_span!("finding sensors");
         let _guard = span.enter();
-        let mut sensor_paths = HashMap::new();
+        if label_cache.hwmon_names != *hwmon_names {
+            label_cache.invalidate();
+            label_cache.hwmon_names = hwmon_names.clone();
+        }
+        let mut sensor_inputs = HashMap::new();
         for (name, sensor) in self.sensors.iter() {
-            match sensor {
-                Sensor::ByNameLabel { hwmon_name, label } => {
-                    let span = debug_span!(
-                        "sensor",
-                        hwmon_name = hwmon_name.as_str(),
-                        label = label.as_str()
-                    );
+            match &sensor.source {
+                SensorSource::ByLabel { hwmon, label } => {
+                    let span = debug_span!("sensor", %hwmon, label = label.as_str());
                     let _guard = span.enter();
-                    let (hwmon_index, _) = hwmon_names
-                        .iter()
-                        .enumerate()
-                        .find(|(_i, name)| *name == hwmon_name)
-                        .ok_or_else(|| Error::HwmonNameNotFound(hwmon_name.clone()))?;
-                    let mut sensor_index = None;
-
-                    for entry in read_dir(format!("/sys/class/hwmon/hwmon{}/", hwmon_index))? {
-                        let _span = debug_span!("checking entry");
-                        let entry = entry?;
-                        let os_file_name = entry.file_name();
-                        let file_name = os_file_name.to_str().unwrap();
-
-                        if !file_name.starts_with("temp") || !file_name.ends_with("_label") {
-                            continue;
+                    let hwmon_index = self.find_hwmon(sysfs, hwmon_names, hwmon)?;
+                    let key = (hwmon_index, label.clone(), self.ignore_case);
+                    let input_path = |sensor_index| {
+                        PathBuf::from(format!(
+                            "/sys/class/hwmon/hwmon{}/temp{}_input",
+                            hwmon_index, sensor_index
+                        ))
+                    };
+
+                    let cached = label_cache
+                        .indices
+                        .get(&key)
+                        .map(|&index| input_path(index))
+                        .filter(|path| sysfs.exists(path));
+                    let path = match cached {
+                        Some(path) => {
+                            debug!(?path, "using cached sensor index");
+                            path
                         }
-                        debug!(file_name, "found temp sensor");
-
-                        let this_label = read_to_string(entry.path())?.trim().to_owned();
-                        if this_label == *label {
-                            let index: usize = file_name
-                                .trim_start_matches("temp")
-                                .trim_end_matches("_label")
-                                .parse()
-                                .unwrap();
-                            sensor_index = Some(index);
+                        // either not looked up yet, or the sensors moved without the hwmons
+                        // changing, so the cached index is stale
+                        None => {
+                            label_cache.indices.remove(&key);
+                            let index = self.find_label(sysfs, hwmon_index, label)?;
+                            let path = input_path(index);
+                            if !sysfs.exists(&path) {
+                                return Err(Error::HwmonSensorNotFound);
+                            }
+                            label_cache.indices.insert(key, index);
+                            path
                         }
-                    }
-
-                    if sensor_index.is_none() {
-                        return Err(Error::HwmonSensorNotFound);
-                    }
-
-                    let path = PathBuf::from(format!(
-                        "/sys/class/hwmon/hwmon{}/temp{}_input",
-                        hwmon_index,
-                        sensor_index.unwrap()
-                    ));
-                    if !path.exists() {
-                        panic!("sensor has label but no input");
-                    }
-                    sensor_paths.insert(name.clone(), path);
+                    };
+                    sensor_inputs.insert(name.clone(), SensorInput::File(path));
                 }
-                Sensor::ByNameIndex { hwmon_name, index } => {
-                    let (hwmon_index, _) = hwmon_names
-                        .iter()
-                        .enumerate()
-                        .find(|(_i, name)| *name == hwmon_name)
-                        .ok_or_else(|| Error::HwmonNameNotFound(hwmon_name.clone()))?;
+                SensorSource::ByIndex { hwmon, index } => {
+                    let hwmon_index = self.find_hwmon(sysfs, hwmon_names, hwmon)?;
 
                     let path = PathBuf::from(format!(
                         "/sys/class/hwmon/hwmon{}/temp{}_input",
                         hwmon_index, index
                     ));
-                    if !path.exists() {
+                    if !sysfs.exists(&path) {
                         return Err(Error::HwmonSensorNotFound);
                     }
-                    sensor_paths.insert(name.clone(), path);
+                    sensor_inputs.insert(name.clone(), SensorInput::File(path));
+                }
+                // the file might only be created later, e.g. by a script, so don't insist here
+                SensorSource::File { path } => {
+                    sensor_inputs.insert(name.clone(), SensorInput::File(path.clone()));
+                }
+                SensorSource::ThermalZone { thermal_zone } => {
+                    let span = debug_span!("sensor", thermal_zone = thermal_zone.as_str());
+                    let _guard = span.enter();
+                    let path = find_thermal_zone(sysfs, thermal_zone)?;
+                    sensor_inputs.insert(name.clone(), SensorInput::File(path));
+                }
+                #[cfg(feature = "nvml")]
+                SensorSource::Nvml { nvml_gpu } => {
+                    sensor_inputs.insert(name.clone(), SensorInput::Nvml(*nvml_gpu));
+                }
+                #[cfg(not(feature = "nvml"))]
+                SensorSource::Nvml { .. } => return Err(Error::NvmlDisabled),
+            }
+        }
+        Ok(sensor_inputs)
+    }
+
+    /// Builds a fake sysfs with every hwmon, sensor and fan the config refers to, so that the
+    /// config can run without the hardware. Sensor inputs start out empty, fans start stopped, and
+    /// tachometers always read `FAKE_RPM`.
+    pub(crate) fn fake_sysfs(&self) -> Result<FakeSysfs, Error> {
+        let sysfs = FakeSysfs::default();
+        let hwmons = self
+            .sensors
+            .values()
+            .filter_map(|sensor| sensor.source.hwmon())
+            .chain(self.fans.values().map(|fan| &fan.path.hwmon));
+        // how many hwmons need each name, so that every instance exists
+        let mut name_counts: BTreeMap<&str, usize> = BTreeMap::new();
+        let mut devices = BTreeSet::new();
+        for hwmon in hwmons {
+            match hwmon {
+                HwmonId::Name {
+                    hwmon_name,
+                    instance,
+                } => {
+                    let count = name_counts.entry(hwmon_name).or_insert(0);
+                    *count = (*count).max(instance.unwrap_or(0) + 1);
+                }
+                HwmonId::Device { hwmon_device } => {
+                    devices.insert(hwmon_device.as_str());
+                }
+            }
+        }
+        let mut hwmon_names = BTreeMap::new();
+        let names = name_counts
+            .into_iter()
+            .flat_map(|(name, count)| std::iter::repeat_n((name, None), count))
+            .chain(devices.into_iter().map(|device| ("fake", Some(device))));
+        for (index, (name, device)) in names.enumerate() {
+            let dir = format!("/sys/class/hwmon/hwmon{}", index);
+            sysfs.insert(format!("{}/name", dir), format!("{}\n", name));
+            if let Some(device) = device {
+                let target = if device.starts_with('/') {
+                    PathBuf::from(device)
+                } else {
+                    Path::new("/sys/devices").join(device)
+                };
+                sysfs.link(format!("{}/device", dir), target);
+            }
+            hwmon_names.insert(index, name.to_owned());
+        }
+
+        // sensors by index go first, so that sensors by label can have whatever indices are left
+        let mut used_indices: HashMap<usize, BTreeSet<usize>> = HashMap::new();
+        for sensor in self.sensors.values() {
+            if let SensorSource::ByIndex { hwmon, index } = &sensor.source {
+                let hwmon_index = self.find_hwmon(&sysfs, &hwmon_names, hwmon)?;
+                sysfs.insert(
+                    format!("/sys/class/hwmon/hwmon{}/temp{}_input", hwmon_index, index),
+                    "",
+                );
+                used_indices.entry(hwmon_index).or_default().insert(*index);
+            }
+        }
+        let mut thermal_zones = 0;
+        for (name, sensor) in self.sensors.iter() {
+            match &sensor.source {
+                SensorSource::ByLabel { hwmon, label } => {
+                    let hwmon_index = self.find_hwmon(&sysfs, &hwmon_names, hwmon)?;
+                    let used = used_indices.entry(hwmon_index).or_default();
+                    let index = (1..).find(|index| !used.contains(index)).unwrap();
+                    used.insert(index);
+                    let prefix = format!("/sys/class/hwmon/hwmon{}/temp{}", hwmon_index, index);
+                    sysfs.insert(format!("{}_label", prefix), format!("{}\n", label));
+                    sysfs.insert(format!("{}_input", prefix), "");
+                }
+                SensorSource::ByIndex { .. } => {}
+                SensorSource::File { path } => sysfs.insert(path.clone(), ""),
+                SensorSource::ThermalZone { thermal_zone } => {
+                    let dir = format!("/sys/class/thermal/thermal_zone{}", thermal_zones);
+                    sysfs.insert(format!("{}/type", dir), format!("{}\n", thermal_zone));
+                    sysfs.insert(format!("{}/temp", dir), "");
+                    thermal_zones += 1;
                 }
+                SensorSource::Nvml { .. } => return Err(Error::NotFakeable(name.clone())),
+            }
+        }
+
+        for fan in self.fans.values() {
+            let hwmon_index = self.find_hwmon(&sysfs, &hwmon_names, &fan.path.hwmon)?;
+            let dir = format!("/sys/class/hwmon/hwmon{}", hwmon_index);
+            sysfs.insert(format!("{}/pwm{}", dir, fan.path.index), "0\n");
+            sysfs.insert(format!("{}/pwm{}_enable", dir, fan.path.index), "2\n");
+            if let Some(tach_index) = fan.path.tach_index {
+                sysfs.insert(
+                    format!("{}/fan{}_input", dir, tach_index),
+                    format!("{}\n", FAKE_RPM),
+                );
             }
         }
-        Ok(sensor_paths)
+        Ok(sysfs)
     }
 
+    /// Opens every configured fan. Fans in `old_fans` that would be opened identically are taken
+    /// as they are rather than being reopened. The rest are left in `old_fans` for the caller to
+    /// release, unless a new fan needs their path.
     pub(crate) fn find_fans(
         &self,
-        hwmon_names: &[String],
+        sysfs: &SharedSysfs,
+        hwmon_names: &BTreeMap<usize, String>,
+        dry_run: bool,
+        old_fans: &mut HashMap<String, ControlledFan>,
     ) -> Result<HashMap<String, ControlledFan>, Error> {
         let span = debug_span!("finding fans");
         let _guard = span.enter();
-        let mut fans = HashMap::new();
+        let mut resolved: Vec<(&String, String, Option<String>, FanOptions)> =
+            Vec::with_capacity(self.fans.len());
         for (name, fan) in self.fans.iter() {
             let FanPath {
-                ref hwmon_name,
+                ref hwmon,
                 index,
+                tach_index,
             } = fan.path;
-            let span = debug_span!("fan", hwmon_name = hwmon_name.as_str(), index);
+            let span = debug_span!("fan", %hwmon, index);
             let _guard = span.enter();
-            let (hwmon_index, _) = hwmon_names
+            let hwmon_index = self.find_hwmon(&**sysfs, hwmon_names, hwmon)?;
+
+            let path_prefix = format!("/sys/class/hwmon/hwmon{}/pwm{}", hwmon_index, index);
+            let tach_path = tach_index.map(|tach_index| {
+                format!(
+                    "/sys/class/hwmon/hwmon{}/fan{}_input",
+                    hwmon_index, tach_index
+                )
+            });
+            let options = FanOptions {
+                enable_mode: fan.enable_mode,
+                optional_enable: fan.optional_enable,
+                invert: fan.invert,
+                dry_run,
+                shutdown_mode: self.shutdown_mode,
+                failsafe_pwm: self.failsafe_pwm,
+            };
+            if let Some((other, ..)) = resolved
                 .iter()
-                .enumerate()
-                .find(|(_i, name)| *name == hwmon_name)
-                .ok_or_else(|| Error::HwmonNameNotFound(hwmon_name.clone()))?;
+                .find(|(_, other_path, ..)| *other_path == path_prefix)
+            {
+                return Err(Error::DuplicateFanPath {
+                    first: (*other).clone(),
+                    second: name.clone(),
+                    path: path_prefix,
+                });
+            }
+            resolved.push((name, path_prefix, tach_path, options));
+        }
 
-            let fan = ControlledFan::new(format!(
-                "/sys/class/hwmon/hwmon{}/pwm{}",
-                hwmon_index, index
-            ))?;
-            fans.insert(name.clone(), fan);
+        // open new fans before taking any old ones, so that on failure old_fans is left as it was
+        let mut fans = HashMap::with_capacity(resolved.len());
+        for (name, path_prefix, tach_path, options) in resolved.iter() {
+            let keep = old_fans
+                .get(*name)
+                .is_some_and(|old_fan| old_fan.matches(path_prefix, tach_path.as_deref(), options));
+            if keep {
+                continue;
+            }
+            // an old fan on the same path must let go first, or the new one would see our manual
+            // mode as the mode to restore
+            old_fans.retain(|_, old_fan| old_fan.path() != path_prefix);
+            let fan = ControlledFan::new(
+                Arc::clone(sysfs),
+                path_prefix.clone(),
+                tach_path.clone(),
+                options.clone(),
+            )
+            .map_err(|error| error.in_fan(name))?;
+            fans.insert((*name).clone(), fan);
+        }
+        for (name, ..) in resolved {
+            if fans.contains_key(name) {
+                continue;
+            }
+            if let Some(fan) = old_fans.remove(name) {
+                debug!(name = name.as_str(), "keeping fan open");
+                fans.insert(name.clone(), fan);
+            }
         }
 
         Ok(fans)
     }
 }
+
+/// Whether `weights` are all finite and not negative, and not all zero.
+fn weights_are_valid(weights: impl Iterator<Item = f32> + Clone) -> bool {
+    weights
+        .clone()
+        .all(|weight| weight.is_finite() && weight >= 0.0)
+        && weights.sum::<f32>() != 0.0
+}
+
+fn parse_curve(name: &str, curve_spec: &CurveSpec) -> Result<Curve, Error> {
+    let mut curve = Vec::<Point>::with_capacity(curve_spec.points().len());
+    for point_spec in curve_spec.points().iter() {
+        trace!(point_spec = point_spec.as_str(), "parsing point_spec...");
+        let (temp, fan_speed) = {
+            let mut iter = point_spec.split('/');
+            let temp = parse_temp(iter.next().ok_or(Error::InvalidPointSpec)?)?;
+            let fan_speed = parse_fan_speed(iter.next().ok_or(Error::InvalidPointSpec)?)?;
+            (temp, fan_speed)
+        };
+        curve.push(Point { temp, fan_speed });
+    }
+    if curve.is_empty() {
+        return Err(Error::EmptyCurve(name.to_owned()));
+    }
+    if curve.windows(2).any(|w| w[0].temp > w[1].temp) {
+        return Err(Error::UnsortedCurve(name.to_owned()));
+    }
+    // this would make curve_lerp divide by zero
+    if let Some(w) = curve.windows(2).find(|w| w[0].temp == w[1].temp) {
+        return Err(Error::DuplicateCurveTemp {
+            curve: name.to_owned(),
+            temp: w[0].temp as f64 / 1000.0,
+        });
+    }
+    Ok(Curve::new(curve, curve_spec.mode()))
+}
+
+fn read_config(path: &Path) -> Result<String, Error> {
+    let mut file = File::open(path).map_err(|error| Error::ConfigOpen {
+        path: path.display().to_string(),
+        error,
+    })?;
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+    Ok(contents)
+}
+
+fn parse_toml(path: &Path, contents: &str) -> Result<toml::value::Table, Error> {
+    toml::from_str(contents).map_err(|error| Error::toml(path, error))
+}
+
+/// Works out which of `files`, the base config and then its drop-ins, `error` from deserializing
+/// them merged came from. The merged config has no locations, but deserializing each file on its
+/// own gives the same error, with a line and column, for the file the bad key is in. Later files
+/// are tried first, since their keys win.
+fn locate_error(files: &[(PathBuf, String)], error: TomlError) -> Error {
+    let message = error.to_string();
+    for (path, contents) in files.iter().rev() {
+        if let Err(file_error) = toml::from_str::<Config>(contents) {
+            if file_error.line_col().is_some() && file_error.to_string().starts_with(&message) {
+                return Error::toml(path, file_error);
+            }
+        }
+    }
+    Error::toml(&files[0].0, error)
+}
+
+/// Merges a drop-in config into `base`. Tables like `[sensors]` are extended, while anything else
+/// is replaced.
+fn merge_toml(base: &mut toml::value::Table, drop_in: toml::value::Table) {
+    for (key, value) in drop_in {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base_table)), toml::Value::Table(table)) => {
+                for (name, value) in table {
+                    if base_table.insert(name.clone(), value).is_some() {
+                        warn!(
+                            key = key.as_str(),
+                            name = name.as_str(),
+                            "drop-in replaces existing entry"
+                        );
+                    }
+                }
+            }
+            (existing, value) => {
+                if existing.is_some() {
+                    warn!(key = key.as_str(), "drop-in replaces existing value");
+                }
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Parses a temperature like `30C`, `-10C`, `42.5C` or `86F` into millidegrees celsius, which
+/// linux works in.
+fn parse_temp(temp_spec: &str) -> Result<i32, Error> {
+    parse_millidegrees(temp_spec, false)
+}
+
+/// Parses a difference between temperatures like `2C` or `3.6F` into millidegrees celsius, where
+/// unlike `parse_temp`, 0F is no change.
+fn parse_temp_difference(temp_spec: &str) -> Result<i32, Error> {
+    parse_millidegrees(temp_spec, true)
+}
+
+fn parse_millidegrees(temp_spec: &str, difference: bool) -> Result<i32, Error> {
+    let (number, fahrenheit) = match temp_spec.strip_suffix('F') {
+        Some(v) => (v, true),
+        None => (temp_spec.trim_end_matches('C'), false),
+    };
+    let degrees: f64 = number.parse().map_err(|_| Error::InvalidPointSpec)?;
+    // convert to celsius before scaling, so rounding only happens once
+    let celsius = match (fahrenheit, difference) {
+        (true, false) => (degrees - 32.0) * 5.0 / 9.0,
+        (true, true) => degrees * 5.0 / 9.0,
+        (false, _) => degrees,
+    };
+    let millidegrees = (celsius * 1000.0).round();
+    if !(millidegrees >= i32::MIN as f64 && millidegrees <= i32::MAX as f64) {
+        return Err(Error::TempOutOfRange(temp_spec.to_owned()));
+    }
+    Ok(millidegrees as i32)
+}
+
+/// Parses a fan speed like `50%` or `128pwm` into the 0-255 range linux works in.
+/// A temperature in the config, either a number of millidegrees celsius like the readings, or a
+/// string with a unit like the points of a curve.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TempSpec {
+    Millidegrees(i32),
+    Text(String),
+}
+
+impl TempSpec {
+    fn parse<E: serde::de::Error>(self, parse: fn(&str) -> Result<i32, Error>) -> Result<i32, E> {
+        match self {
+            TempSpec::Millidegrees(temp) => Ok(temp),
+            TempSpec::Text(text) => parse(&text).map_err(|error| match error {
+                Error::TempOutOfRange(_) => E::custom(error),
+                _ => E::custom(format!(
+                    "invalid temperature {:?}, expected millidegrees or e.g. \"40C\" or \"104F\"",
+                    text
+                )),
+            }),
+        }
+    }
+}
+
+fn deserialize_temp<'de, D: Deserializer<'de>>(deserializer: D) -> Result<i32, D::Error> {
+    TempSpec::deserialize(deserializer)?.parse(parse_temp)
+}
+
+fn deserialize_optional_temp<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<i32>, D::Error> {
+    Option::<TempSpec>::deserialize(deserializer)?
+        .map(|spec| spec.parse(parse_temp))
+        .transpose()
+}
+
+fn deserialize_temp_difference<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<i32, D::Error> {
+    TempSpec::deserialize(deserializer)?.parse(parse_temp_difference)
+}
+
+fn parse_fan_speed(speed_spec: &str) -> Result<u8, Error> {
+    let out_of_range = || Error::SpeedOutOfRange(speed_spec.to_owned());
+    if let Some(raw) = speed_spec.strip_suffix("pwm") {
+        let raw: i64 = raw.parse().map_err(|_| Error::InvalidPointSpec)?;
+        if !(0..=255).contains(&raw) {
+            return Err(out_of_range());
+        }
+        return Ok(raw as u8);
+    }
+    let fan_percent: i64 = speed_spec
+        .trim_end_matches('%')
+        .parse()
+        .map_err(|_| Error::InvalidPointSpec)?;
+    if !(0..=100).contains(&fan_percent) {
+        return Err(out_of_range());
+    }
+    Ok((fan_percent * 255 / 100) as u8)
+}
+
+/// Whether the `device` of `/sys/class/hwmon/hwmon{index}` is `device`, either as a full path or
+/// as its last component.
+fn device_matches(sysfs: &dyn Sysfs, index: usize, device: &str) -> bool {
+    let link = format!("/sys/class/hwmon/hwmon{}/device", index);
+    match sysfs.canonicalize(Path::new(&link)) {
+        Ok(path) => {
+            path == Path::new(device) || path.file_name().is_some_and(|name| name == device)
+        }
+        // not every hwmon has a device
+        Err(_) => false,
+    }
+}
+
+/// Finds the `temp` file of the thermal zone whose `type` is `zone_type`.
+fn find_thermal_zone(sysfs: &dyn Sysfs, zone_type: &str) -> Result<PathBuf, Error> {
+    for entry in sysfs.read_dir(Path::new("/sys/class/thermal"))? {
+        if !entry
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with("thermal_zone"))
+        {
+            continue;
+        }
+        let this_type = sysfs.read_to_string(&entry.join("type"))?;
+        if this_type.trim() == zone_type {
+            debug!(path = ?entry, "found thermal zone");
+            return Ok(entry.join("temp"));
+        }
+    }
+    Err(Error::ThermalZoneNotFound(zone_type.to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{hwmon_names, sysfs::FakeSysfs};
+
+    fn parse(config: &str) -> Config {
+        toml::from_str(config).unwrap()
+    }
+
+    /// Where each sensor of `config` is read from, by name.
+    fn sensor_paths(
+        config: &Config,
+        sysfs: &FakeSysfs,
+        label_cache: &mut LabelCache,
+    ) -> BTreeMap<String, String> {
+        let hwmon_names = hwmon_names(sysfs).unwrap();
+        config
+            .find_sensors(sysfs, &hwmon_names, label_cache)
+            .unwrap()
+            .into_iter()
+            .map(|(name, input)| (name, input.to_string()))
+            .collect()
+    }
+
+    const SENSORS: &str = r#"
+        [sensors.package]
+        hwmon_name = "coretemp"
+        label = "Package id 0"
+        [sensors.core]
+        hwmon_name = "coretemp"
+        index = 1
+        [sensors.gpu]
+        hwmon_device = "0000:03:00.0"
+        index = 1
+        [sensors.acpi]
+        thermal_zone = "acpitz"
+        [composites]
+        [curves]
+        [fans]
+    "#;
+
+    #[test]
+    fn fake_sysfs_has_every_sensor() {
+        let config = parse(SENSORS);
+        let sysfs = config.fake_sysfs().unwrap();
+        let paths = sensor_paths(&config, &sysfs, &mut LabelCache::default());
+        assert_eq!(paths["core"], "/sys/class/hwmon/hwmon0/temp1_input");
+        // the label gets the first index that isn't taken
+        assert_eq!(paths["package"], "/sys/class/hwmon/hwmon0/temp2_input");
+        assert_eq!(paths["gpu"], "/sys/class/hwmon/hwmon1/temp1_input");
+        assert_eq!(paths["acpi"], "/sys/class/thermal/thermal_zone0/temp");
+    }
+
+    /// A coretemp hwmon with the package sensor at `index`, and an input for it only if `input`.
+    fn coretemp(index: usize, input: bool) -> FakeSysfs {
+        let sysfs = FakeSysfs::default();
+        let prefix = format!("/sys/class/hwmon/hwmon0/temp{}", index);
+        sysfs.insert("/sys/class/hwmon/hwmon0/name", "coretemp\n");
+        sysfs.insert(format!("{}_label", prefix), "Package id 0\n");
+        if input {
+            sysfs.insert(format!("{}_input", prefix), "40000\n");
+        }
+        sysfs
+    }
+
+    const PACKAGE: &str = r#"
+        [sensors.package]
+        hwmon_name = "coretemp"
+        label = "Package id 0"
+        [composites]
+        [curves]
+        [fans]
+    "#;
+
+    #[test]
+    fn stale_label_cache_is_looked_up_again() {
+        let config = parse(PACKAGE);
+        let mut label_cache = LabelCache::default();
+        let paths = sensor_paths(&config, &coretemp(1, true), &mut label_cache);
+        assert_eq!(paths["package"], "/sys/class/hwmon/hwmon0/temp1_input");
+        // same hwmons, so the cache is kept, but the sensor has moved
+        let paths = sensor_paths(&config, &coretemp(3, true), &mut label_cache);
+        assert_eq!(paths["package"], "/sys/class/hwmon/hwmon0/temp3_input");
+    }
+
+    #[test]
+    fn label_without_input_is_an_error() {
+        let config = parse(PACKAGE);
+        let sysfs = coretemp(1, false);
+        let hwmon_names = hwmon_names(&sysfs).unwrap();
+        let res = config.find_sensors(&sysfs, &hwmon_names, &mut LabelCache::default());
+        assert!(matches!(res, Err(Error::HwmonSensorNotFound)));
+    }
+
+    fn composite_weights(weights: &str) -> Result<(), Error> {
+        parse(&format!(
+            r#"
+            [sensors]
+            [composites.case]
+            inputs = ["cpu", "gpu"]
+            mode = "weightedmean"
+            weights = {}
+            [curves]
+            [fans]
+            "#,
+            weights
+        ))
+        .validate_composites()
+    }
+
+    #[test]
+    fn pwm_limits_above_100_percent_are_rejected() {
+        assert!(parse(&fan_config("min_pwm = 20\nmax_pwm = 100"))
+            .validate_fans()
+            .is_ok());
+        for (options, percent) in [("min_pwm = 101", "101%"), ("max_pwm = 200", "200%")] {
+            match parse(&fan_config(options)).validate_fans() {
+                Err(Error::Fan { error, .. }) => {
+                    assert!(matches!(*error, Error::SpeedOutOfRange(v) if v == percent))
+                }
+                _ => panic!("{} was accepted", options),
+            }
+        }
+    }
+
+    #[test]
+    fn composite_weights_are_checked() {
+        assert!(composite_weights("[]").is_ok());
+        assert!(composite_weights("[1, 0]").is_ok());
+        for weights in ["[-1, 2]", "[nan, 1]", "[inf, 1]", "[0, 0]"] {
+            assert!(matches!(
+                composite_weights(weights),
+                Err(Error::InvalidWeight { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn calibrating_does_not_overflow() {
+        let sensors = parse(
+            r#"
+            [sensors.hot]
+            path = "/hot"
+            offset = 5000
+            [sensors.cold]
+            path = "/cold"
+            offset = "-5C"
+            scale = 2.0
+            [composites]
+            [curves]
+            [fans]
+            "#,
+        )
+        .sensors;
+        assert_eq!(sensors["hot"].calibrate(40000), 45000);
+        assert_eq!(sensors["hot"].calibrate(i32::MAX - 1), i32::MAX);
+        assert_eq!(sensors["cold"].calibrate(20000), 35000);
+        assert_eq!(sensors["cold"].calibrate(i32::MIN / 2 + 1), i32::MIN);
+    }
+
+    fn schedule_entry(start: u32, end: u32) -> ScheduleEntry {
+        ScheduleEntry {
+            start,
+            end,
+            profile: "quiet".to_owned(),
+        }
+    }
+
+    #[test]
+    fn schedule_past_midnight() {
+        // 22:00-06:00
+        let entry = schedule_entry(22 * 60, 6 * 60);
+        assert!(!entry.covers(22 * 60 - 1));
+        assert!(entry.covers(22 * 60));
+        assert!(entry.covers(0));
+        assert!(entry.covers(6 * 60 - 1));
+        assert!(!entry.covers(6 * 60));
+        assert!(!entry.covers(12 * 60));
+    }
+
+    #[test]
+    fn schedule_within_a_day() {
+        // 09:00-17:00
+        let entry = schedule_entry(9 * 60, 17 * 60);
+        assert!(!entry.covers(9 * 60 - 1));
+        assert!(entry.covers(9 * 60));
+        assert!(entry.covers(17 * 60 - 1));
+        assert!(!entry.covers(17 * 60));
+        assert!(!entry.covers(0));
+        // equal times cover the whole day
+        let entry = schedule_entry(9 * 60, 9 * 60);
+        assert!((0..24 * 60).all(|minute| entry.covers(minute)));
+    }
+
+    #[test]
+    fn temps_below_zero() {
+        assert_eq!(parse_temp("-10C").unwrap(), -10000);
+        assert_eq!(parse_temp("-0.5C").unwrap(), -500);
+        assert_eq!(parse_temp("-4F").unwrap(), -20000);
+        assert_eq!(parse_temp("14F").unwrap(), -10000);
+    }
+
+    #[test]
+    fn curves_can_start_below_zero() {
+        let spec = CurveSpec::Points(vec!["-10C/20%".to_owned(), "50F/60%".to_owned()]);
+        let curve = parse_curve("cold", &spec).unwrap();
+        assert_eq!(curve.temp_range(), (-10000, 10000));
+        assert_eq!(curve.speed_at(-30000), 51);
+        assert_eq!(curve.speed_at(0), 102);
+    }
+
+    /// A config with a single fan, which has `options` added to it.
+    fn fan_config(options: &str) -> String {
+        format!(
+            r#"
+            [sensors]
+            [composites]
+            [curves]
+            [fans.f]
+            path = {{ hwmon_name = "x", index = 1 }}
+            input = "cpu"
+            curve = "cpu"
+            {}
+            "#,
+            options
+        )
+    }
+
+    fn fan_with(options: &str) -> Fan {
+        parse(&fan_config(options)).fans.remove("f").unwrap()
+    }
+
+    #[test]
+    fn temps_in_millidegrees_or_with_a_unit() {
+        assert_eq!(fan_with("stop_below = 40000").stop_below, Some(40000));
+        assert_eq!(fan_with(r#"stop_below = "40C""#).stop_below, Some(40000));
+        assert_eq!(fan_with(r#"stop_below = "104F""#).stop_below, Some(40000));
+        assert_eq!(fan_with("").stop_below, None);
+        // a difference in fahrenheit has no offset from zero
+        assert_eq!(fan_with(r#"hysteresis = "3.6F""#).hysteresis, 2000);
+        assert_eq!(fan_with(r#"hysteresis = "2C""#).hysteresis, 2000);
+        assert_eq!(fan_with("").hysteresis, 0);
+        let error = toml::from_str::<Config>(&fan_config(r#"stop_below = "warm""#)).err();
+        assert!(error.unwrap().to_string().contains("invalid temperature"));
+    }
+}
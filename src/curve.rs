@@ -0,0 +1,174 @@
+use tracing::{trace, trace_span};
+
+use crate::config::CurveMode;
+
+#[derive(Debug)]
+pub struct Point {
+    pub temp: i32,
+    pub fan_speed: u8,
+}
+
+pub struct Curve {
+    points: Vec<Point>,
+    mode: CurveMode,
+    /// The slope of the curve at each point, in fan speed per millidegree. Only calculated for
+    /// smooth curves.
+    tangents: Vec<f64>,
+}
+
+impl Curve {
+    pub fn new(points: Vec<Point>, mode: CurveMode) -> Self {
+        let tangents = match mode {
+            CurveMode::Smooth => pchip_tangents(&points),
+            CurveMode::Linear | CurveMode::Step => Vec::new(),
+        };
+        Self {
+            points,
+            mode,
+            tangents,
+        }
+    }
+
+    /// The temperatures of the first and last points, in millidegrees.
+    pub fn temp_range(&self) -> (i32, i32) {
+        (self.points[0].temp, self.points[self.points.len() - 1].temp)
+    }
+
+    pub fn speed_at(&self, temp: i32) -> u8 {
+        match self.mode {
+            CurveMode::Linear => curve_lerp(temp, &self.points),
+            CurveMode::Smooth => curve_pchip(temp, &self.points, &self.tangents),
+            CurveMode::Step => curve_step(temp, &self.points),
+        }
+    }
+}
+
+/// Straight lines between the points of `curve`. Outside the curve, the speed of the nearest end
+/// is used, wherever either end is relative to zero.
+pub fn curve_lerp(temp: i32, curve: &[Point]) -> u8 {
+    let span = trace_span!("curve lerp");
+    let _guard = span.enter();
+    if temp < curve[0].temp {
+        return curve[0].fan_speed;
+    }
+    for window in curve.windows(2) {
+        let (lower, upper) = (&window[0], &window[1]);
+        if temp >= lower.temp && temp < upper.temp {
+            trace!(?lower, ?upper, "temp in window");
+            let normalised_temp = (temp - lower.temp) as isize;
+            // may be negative if the fan slows down as it gets hotter
+            let upscale_factor = upper.fan_speed as isize - lower.fan_speed as isize;
+            let downscale_factor = (upper.temp - lower.temp) as isize;
+            let fan_speed =
+                normalised_temp * upscale_factor / downscale_factor + lower.fan_speed as isize;
+            return fan_speed as u8;
+        }
+    }
+    curve[curve.len() - 1].fan_speed
+}
+
+/// Each point's speed holds until the next point's temperature is reached. Below the first point
+/// the fan is stopped.
+fn curve_step(temp: i32, curve: &[Point]) -> u8 {
+    curve
+        .iter()
+        .take_while(|point| point.temp <= temp)
+        .last()
+        .map_or(0, |point| point.fan_speed)
+}
+
+/// Monotone cubic (PCHIP) interpolation, using tangents from `pchip_tangents`. The result passes
+/// through every point and never overshoots between them.
+fn curve_pchip(temp: i32, curve: &[Point], tangents: &[f64]) -> u8 {
+    let span = trace_span!("curve pchip");
+    let _guard = span.enter();
+    if temp < curve[0].temp {
+        return curve[0].fan_speed;
+    }
+    for (i, window) in curve.windows(2).enumerate() {
+        let (lower, upper) = (&window[0], &window[1]);
+        if temp >= lower.temp && temp < upper.temp {
+            trace!(?lower, ?upper, "temp in window");
+            let h = (upper.temp - lower.temp) as f64;
+            let t = (temp - lower.temp) as f64 / h;
+            let (t2, t3) = (t * t, t * t * t);
+            let fan_speed = (2.0 * t3 - 3.0 * t2 + 1.0) * lower.fan_speed as f64
+                + (t3 - 2.0 * t2 + t) * h * tangents[i]
+                + (-2.0 * t3 + 3.0 * t2) * upper.fan_speed as f64
+                + (t3 - t2) * h * tangents[i + 1];
+            return fan_speed.round().clamp(0.0, 255.0) as u8;
+        }
+    }
+    curve[curve.len() - 1].fan_speed
+}
+
+/// Fritsch-Carlson tangents, which keep the interpolated curve monotonic wherever the points are.
+fn pchip_tangents(curve: &[Point]) -> Vec<f64> {
+    if curve.len() < 2 {
+        return vec![0.0; curve.len()];
+    }
+    let widths: Vec<f64> = curve
+        .windows(2)
+        .map(|w| (w[1].temp - w[0].temp) as f64)
+        .collect();
+    let secants: Vec<f64> = curve
+        .windows(2)
+        .zip(widths.iter())
+        .map(|(w, h)| (w[1].fan_speed as f64 - w[0].fan_speed as f64) / h)
+        .collect();
+
+    let mut tangents = Vec::with_capacity(curve.len());
+    tangents.push(secants[0]);
+    for k in 1..curve.len() - 1 {
+        let (d0, d1) = (secants[k - 1], secants[k]);
+        if d0 * d1 <= 0.0 {
+            // local extremum or flat segment - keep it flat so we don't overshoot
+            tangents.push(0.0);
+        } else {
+            let (h0, h1) = (widths[k - 1], widths[k]);
+            let w0 = 2.0 * h1 + h0;
+            let w1 = h1 + 2.0 * h0;
+            tangents.push((w0 + w1) / (w0 / d0 + w1 / d1));
+        }
+    }
+    tangents.push(secants[secants.len() - 1]);
+    tangents
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn points(points: &[(i32, u8)]) -> Vec<Point> {
+        points
+            .iter()
+            .map(|&(temp, fan_speed)| Point { temp, fan_speed })
+            .collect()
+    }
+
+    #[test]
+    fn lerp_below_a_negative_first_point() {
+        let curve = points(&[(-20000, 50), (20000, 150)]);
+        assert_eq!(curve_lerp(-40000, &curve), 50);
+        assert_eq!(curve_lerp(-20001, &curve), 50);
+        assert_eq!(curve_lerp(-20000, &curve), 50);
+        assert_eq!(curve_lerp(-10000, &curve), 75);
+        assert_eq!(curve_lerp(0, &curve), 100);
+        assert_eq!(curve_lerp(30000, &curve), 150);
+    }
+
+    #[test]
+    fn every_mode_holds_below_a_negative_first_point() {
+        let below = -30000;
+        for mode in [CurveMode::Linear, CurveMode::Smooth] {
+            let curve = Curve::new(points(&[(-20000, 50), (0, 100), (20000, 150)]), mode);
+            assert_eq!(curve.speed_at(below), 50);
+            assert_eq!(curve.speed_at(-20000), 50);
+        }
+        // step curves stop the fan below their first point instead
+        let curve = Curve::new(points(&[(-20000, 50), (0, 100)]), CurveMode::Step);
+        assert_eq!(curve.speed_at(below), 0);
+        assert_eq!(curve.speed_at(-20000), 50);
+        assert_eq!(curve.speed_at(-1), 50);
+    }
+}
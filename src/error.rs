@@ -1,4 +1,7 @@
-use std::{io::Error as IoError, num::ParseIntError};
+// displaydoc 0.2 generates its impl inside a const block
+#![allow(non_local_definitions)]
+
+use std::{io::Error as IoError, num::ParseIntError, path::Path};
 
 use displaydoc::Display;
 use toml::de::Error as TomlError;
@@ -7,26 +10,197 @@ use toml::de::Error as TomlError;
 pub enum Error {
     /// The specified hwmon name "{0}" was not found.
     HwmonNameNotFound(String),
+    /// No hwmon belongs to the device "{0}".
+    HwmonDeviceNotFound(String),
     /// The specified sensor (label or index) was not found.
     HwmonSensorNotFound,
+    /// No thermal zone has the type "{0}".
+    ThermalZoneNotFound(String),
+    /// NVML sensors need whoosh to be built with the "nvml" feature.
+    #[cfg(not(feature = "nvml"))]
+    NvmlDisabled,
+    /// NVML could not be loaded: {0}
+    #[cfg(feature = "nvml")]
+    NvmlUnavailable(String),
+    /// The NVML call {call} failed with code {code}.
+    #[cfg(feature = "nvml")]
+    Nvml { call: &'static str, code: i32 },
     /// One of the curve points defined in the configuration file was invalid.
     InvalidPointSpec,
-    /// A sensor reading was not a valid integer.
-    InvalidReading(ParseIntError),
-    /// A fan mode was not a valid integer.
-    InvalidMode(ParseIntError),
-    /// A fan speed was not a valid integer.
-    InvalidSpeed(ParseIntError),
-
-    /// The configuration file does not contain valid TOML: {0}
-    Toml(toml::de::Error),
+    /// The temperature "{0}" is too far from zero.
+    TempOutOfRange(String),
+    /// The fan speed "{0}" is not between 0% and 100%, or between 0pwm and 255pwm.
+    SpeedOutOfRange(String),
+    /// There is no curve called "{0}".
+    CurveNotFound(String),
+    /// Curve "{0}" has no points.
+    EmptyCurve(String),
+    /// The points of curve "{0}" are not in order of increasing temperature.
+    UnsortedCurve(String),
+    /// Curve "{curve}" has more than one point at {temp}C.
+    DuplicateCurveTemp { curve: String, temp: f64 },
+    /// Sensor "{0}" has a smoothing factor that isn't between 0 and 1.
+    InvalidSmoothing(String),
+    /// Sensor "{0}" has a window of zero readings.
+    InvalidWindow(String),
+    /// Sensor "{0}" is its own fallback, directly or indirectly.
+    FallbackCycle(String),
+    /// Fans "{first}" and "{second}" both control {path}.
+    DuplicateFanPath {
+        first: String,
+        second: String,
+        path: String,
+    },
+    /// Composite "{0}" is its own input, directly or indirectly.
+    CompositeCycle(String),
+    /// Fan "{0}" is in curve mode but has no curve.
+    MissingFanCurve(String),
+    /// Fan "{0}" is in pid mode but has no pid table.
+    MissingPid(String),
+    /// Fan "{0}" is in blend mode but has no blend sources.
+    MissingBlend(String),
+    /// {kind} "{name}" has a weight that is negative or not a number, or only zero weights.
+    InvalidWeight { kind: &'static str, name: String },
+    /// Fan "{0}" has a gamma that isn't a positive number.
+    InvalidGamma(String),
+    /// There is no profile called "{0}".
+    UnknownProfile(String),
+    /// Composite "{composite}" has {weights} weights but {inputs} inputs.
+    WeightCountMismatch {
+        composite: String,
+        weights: usize,
+        inputs: usize,
+    },
+    /// The configuration refers to things that don't exist: {0}
+    DanglingReferences(String),
+    /// The reading "{value}" from {path} is not a number: {error}
+    InvalidReading {
+        path: String,
+        value: String,
+        error: ParseIntError,
+    },
+    /// The mode "{value}" in {path} is not an integer: {error}
+    InvalidMode {
+        path: String,
+        value: String,
+        error: ParseIntError,
+    },
+    /// The speed "{value}" in {path} is not an integer: {error}
+    InvalidSpeed {
+        path: String,
+        value: String,
+        error: ParseIntError,
+    },
+    /// The tachometer reading "{value}" from {path} is not an integer: {error}
+    InvalidRpm {
+        path: String,
+        value: String,
+        error: ParseIntError,
+    },
+    /// Sensor "{name}": {error}
+    Sensor { name: String, error: Box<Error> },
+    /// Fan "{name}": {error}
+    Fan { name: String, error: Box<Error> },
+
+    /// Sensor "{0}" reads from NVML, which can't be simulated.
+    NotFakeable(String),
+    /// Line {line} of the recording is invalid: {error}
+    InvalidRecording {
+        line: usize,
+        error: serde_json::Error,
+    },
+
+    /// Invalid command line arguments: {0}
+    InvalidArgs(String),
+    /// The fancontrol config could not be converted: {0}
+    InvalidFancontrol(String),
+
+    /// Could not open the configuration file "{path}" (set by --config, then WHOOSH_CONFIG, then defaulting to /etc/whoosh.toml): {error}
+    ConfigOpen { path: String, error: IoError },
+    /// The configuration at {location} is invalid: {error}
+    Toml { location: String, error: TomlError },
+    /// The status could not be serialized as JSON: {0}
+    Json(serde_json::Error),
+    /// Could not access {path}: {error}
+    File { path: String, error: IoError },
+    /// The status socket path {0} already exists and isn't a socket, so it won't be replaced.
+    NotASocket(String),
+    /// The thread reading the sensor panicked.
+    ReadPanicked,
+    /// Could not set the speed of the fan at {path}: {error}
+    SpeedWrite { path: String, error: IoError },
     /// An I/O error occurred: {0}
     Io(std::io::Error),
 }
 
-impl From<TomlError> for Error {
-    fn from(e: toml::de::Error) -> Self {
-        Error::Toml(e)
+impl Error {
+    /// Wraps an I/O error with the path of the file it happened on.
+    pub fn file<P: AsRef<Path>>(path: P) -> impl FnOnce(IoError) -> Self {
+        move |error| Error::File {
+            path: path.as_ref().display().to_string(),
+            error,
+        }
+    }
+
+    /// Wraps a TOML error with the file it came from, and where in the file if known.
+    pub fn toml(path: &Path, error: TomlError) -> Self {
+        let location = match error.line_col() {
+            // toml counts from 0, editors count from 1
+            Some((line, col)) => format!("{}:{}:{}", path.display(), line + 1, col + 1),
+            None => path.display().to_string(),
+        };
+        Error::Toml { location, error }
+    }
+
+    /// Whether the error comes from the config or arguments themselves, so retrying won't help
+    /// until someone fixes them. Errors from the hardware might go away on their own.
+    pub fn is_fatal(&self) -> bool {
+        match self {
+            Error::Sensor { error, .. } | Error::Fan { error, .. } => error.is_fatal(),
+            #[cfg(not(feature = "nvml"))]
+            Error::NvmlDisabled => true,
+            Error::InvalidPointSpec
+            | Error::TempOutOfRange(_)
+            | Error::SpeedOutOfRange(_)
+            | Error::EmptyCurve(_)
+            | Error::UnsortedCurve(_)
+            | Error::DuplicateCurveTemp { .. }
+            | Error::InvalidSmoothing(_)
+            | Error::InvalidWindow(_)
+            | Error::FallbackCycle(_)
+            | Error::DuplicateFanPath { .. }
+            | Error::CompositeCycle(_)
+            | Error::MissingFanCurve(_)
+            | Error::MissingPid(_)
+            | Error::MissingBlend(_)
+            | Error::InvalidWeight { .. }
+            | Error::InvalidGamma(_)
+            | Error::UnknownProfile(_)
+            | Error::WeightCountMismatch { .. }
+            | Error::DanglingReferences(_)
+            | Error::InvalidArgs(_)
+            | Error::NotFakeable(_)
+            | Error::NotASocket(_)
+            | Error::ConfigOpen { .. }
+            | Error::Toml { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// Wraps an error with the name of the sensor it happened to.
+    pub fn in_sensor(self, name: &str) -> Self {
+        Error::Sensor {
+            name: name.to_owned(),
+            error: Box::new(self),
+        }
+    }
+
+    /// Wraps an error with the name of the fan it happened to.
+    pub fn in_fan(self, name: &str) -> Self {
+        Error::Fan {
+            name: name.to_owned(),
+            error: Box::new(self),
+        }
     }
 }
 
@@ -1,46 +1,267 @@
-use std::fs::{read_to_string, write};
+use std::{
+    panic,
+    path::Path,
+    sync::{Arc, Mutex},
+    thread,
+};
 
-use tracing::warn;
+use tracing::{error, warn};
 
-use crate::error::Error;
+use crate::{
+    config::ShutdownMode,
+    error::Error,
+    sysfs::{SharedSysfs, Sysfs},
+};
+
+/// The sysfs, path prefix, whether it has an enable file, and options of every fan we currently
+/// control, so that the panic hook can reach them. The hook writes through the fan's own sysfs, so
+/// that a fan on a fake one never touches real hardware.
+static CONTROLLED_FANS: Mutex<Vec<(SharedSysfs, String, bool, FanOptions)>> =
+    Mutex::new(Vec::new());
+
+/// Installs a panic hook that sets every controlled fan to full speed under manual control before
+/// the process dies.
+pub fn install_panic_hook() {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        // if the panic happened while the lock was held, the list itself is still fine
+        let fans = CONTROLLED_FANS.lock().unwrap_or_else(|e| e.into_inner());
+        for (sysfs, path_prefix, has_enable, options) in fans.iter() {
+            let mut enable_path = path_prefix.clone();
+            enable_path.push_str("_enable");
+            let full_speed = options.to_raw(255);
+            let res = if *has_enable {
+                sysfs.write(
+                    Path::new(&enable_path),
+                    &format!("{}\n", options.enable_mode),
+                )
+            } else {
+                Ok(())
+            }
+            .and_then(|_| sysfs.write(Path::new(path_prefix), &format!("{}\n", full_speed)));
+            if let Err(e) = res {
+                error!(path_prefix = path_prefix.as_str(), error = ?e, "failed to set fan to full speed");
+            }
+        }
+        drop(fans);
+        default_hook(info);
+    }));
+}
+
+/// How a `ControlledFan` should treat its hardware.
+#[derive(Clone, PartialEq)]
+pub struct FanOptions {
+    /// The value written to `pwmN_enable` to take manual control.
+    pub enable_mode: u8,
+    /// If set, a missing `pwmN_enable` is tolerated, and the fan is driven through `pwmN` alone.
+    pub optional_enable: bool,
+    /// Whether a higher PWM value makes the fan slower.
+    pub invert: bool,
+    /// If set, the enable mode is never written, so the fan is left under its existing control.
+    pub dry_run: bool,
+    /// What to leave the fan doing when it's dropped.
+    pub shutdown_mode: ShutdownMode,
+    /// The speed the fan is left at if `shutdown_mode` is `Failsafe`.
+    pub failsafe_pwm: u8,
+}
+
+/// The `pwmN_enable` value for automatic control by the hardware.
+const AUTO_MODE: u8 = 2;
+
+impl FanOptions {
+    /// Converts between the logical speed (higher is faster) and the value in `pwmN`. This is its
+    /// own inverse.
+    fn to_raw(&self, speed: u8) -> u8 {
+        if self.invert {
+            255 - speed
+        } else {
+            speed
+        }
+    }
+}
 
 pub struct ControlledFan {
+    sysfs: SharedSysfs,
     path_prefix: String,
-    initial_mode: u8,
+    tach_path: Option<String>,
+    /// The mode in `pwmN_enable` before we took control, or `None` if the fan has no enable file.
+    initial_mode: Option<u8>,
+    initial_speed: u8,
+    options: FanOptions,
 }
 
 impl ControlledFan {
-    pub fn new(path_prefix: String) -> Result<Self, Error> {
+    pub fn new(
+        sysfs: SharedSysfs,
+        path_prefix: String,
+        tach_path: Option<String>,
+        options: FanOptions,
+    ) -> Result<Self, Error> {
         let mut enable_path = path_prefix.clone();
         enable_path.push_str("_enable");
-        let mode_string = read_to_string(&enable_path)?;
-        let initial_mode = mode_string.trim().parse().map_err(Error::InvalidMode)?;
+        let initial_mode = if options.optional_enable && !sysfs.exists(Path::new(&enable_path)) {
+            warn!(
+                path_prefix = path_prefix.as_str(),
+                "fan has no enable file - driving it through its pwm file alone"
+            );
+            None
+        } else {
+            let mode_string = sysfs
+                .read_to_string(Path::new(&enable_path))
+                .map_err(Error::file(&enable_path))?;
+            let mode = mode_string
+                .trim()
+                .parse()
+                .map_err(|error| Error::InvalidMode {
+                    path: enable_path.clone(),
+                    value: mode_string.trim().to_owned(),
+                    error,
+                })?;
+            Some(mode)
+        };
+        let initial_speed = read_speed(&*sysfs, &path_prefix, &options)?;
 
-        write(enable_path, b"1\n")?;
+        if !options.dry_run {
+            if initial_mode.is_some() {
+                sysfs
+                    .write(
+                        Path::new(&enable_path),
+                        &format!("{}\n", options.enable_mode),
+                    )
+                    .map_err(Error::file(&enable_path))?;
+            }
+            CONTROLLED_FANS.lock().unwrap().push((
+                Arc::clone(&sysfs),
+                path_prefix.clone(),
+                initial_mode.is_some(),
+                options.clone(),
+            ));
+        }
 
         Ok(Self {
+            sysfs,
             path_prefix,
+            tach_path,
             initial_mode,
+            initial_speed,
+            options,
         })
     }
 
+    /// The path of the fan's `pwmN` file.
+    pub fn path(&self) -> &str {
+        &self.path_prefix
+    }
+
+    /// Whether this fan was opened with exactly these paths and options, so that it can be kept
+    /// instead of being reopened.
+    pub fn matches(
+        &self,
+        path_prefix: &str,
+        tach_path: Option<&str>,
+        options: &FanOptions,
+    ) -> bool {
+        self.path_prefix == path_prefix
+            && self.tach_path.as_deref() == tach_path
+            && self.options == *options
+    }
+
     pub fn get_speed(&self) -> Result<u8, Error> {
-        let speed_string = read_to_string(&self.path_prefix)?;
-        let speed = speed_string.trim().parse().map_err(Error::InvalidSpeed)?;
-        Ok(speed)
+        read_speed(&*self.sysfs, &self.path_prefix, &self.options)
+    }
+
+    /// The speed the fan was at before we took control of it.
+    pub fn initial_speed(&self) -> u8 {
+        self.initial_speed
+    }
+
+    /// Reads the fan's tachometer, if it has one.
+    pub fn get_rpm(&self) -> Result<Option<u32>, Error> {
+        let tach_path = match &self.tach_path {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        let rpm_string = self
+            .sysfs
+            .read_to_string(Path::new(tach_path))
+            .map_err(Error::file(tach_path))?;
+        let rpm = rpm_string
+            .trim()
+            .parse()
+            .map_err(|error| Error::InvalidRpm {
+                path: tach_path.clone(),
+                value: rpm_string.trim().to_owned(),
+                error,
+            })?;
+        Ok(Some(rpm))
     }
 
     pub fn set_speed(&self, new_speed: u8) -> Result<(), Error> {
-        write(&self.path_prefix, format!("{}\n", new_speed))?;
-        Ok(())
+        self.sysfs
+            .write(
+                Path::new(&self.path_prefix),
+                &format!("{}\n", self.options.to_raw(new_speed)),
+            )
+            .map_err(|error| Error::SpeedWrite {
+                path: self.path_prefix.clone(),
+                error,
+            })
     }
 }
 
+/// Whether `a` and `b` are the same sysfs. Only the data pointers are compared, since the same
+/// type can have more than one vtable.
+fn same_sysfs(a: &SharedSysfs, b: &SharedSysfs) -> bool {
+    Arc::as_ptr(a) as *const () == Arc::as_ptr(b) as *const ()
+}
+
+/// Reads the logical speed of the fan whose `pwmN` file is at `path_prefix`.
+fn read_speed(sysfs: &dyn Sysfs, path_prefix: &str, options: &FanOptions) -> Result<u8, Error> {
+    let speed_string = sysfs
+        .read_to_string(Path::new(path_prefix))
+        .map_err(Error::file(path_prefix))?;
+    let speed = speed_string
+        .trim()
+        .parse()
+        .map_err(|error| Error::InvalidSpeed {
+            path: path_prefix.to_owned(),
+            value: speed_string.trim().to_owned(),
+            error,
+        })?;
+    Ok(options.to_raw(speed))
+}
+
 impl Drop for ControlledFan {
     fn drop(&mut self) {
+        if self.options.dry_run {
+            return;
+        }
+        CONTROLLED_FANS
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            // the same path on another sysfs is another fan
+            .retain(|(sysfs, path_prefix, ..)| {
+                !(same_sysfs(sysfs, &self.sysfs) && *path_prefix == self.path_prefix)
+            });
+        if thread::panicking() {
+            // the panic hook has set this fan to full speed - leave it there
+            return;
+        }
         let mut enable_path = self.path_prefix.clone();
         enable_path.push_str("_enable");
-        let res = write(enable_path, format!("{}\n", self.initial_mode).as_bytes());
+        let (path, value) = match (self.options.shutdown_mode, self.initial_mode) {
+            (ShutdownMode::Restore, Some(initial_mode)) => (&enable_path, initial_mode),
+            (ShutdownMode::Auto, Some(_)) => (&enable_path, AUTO_MODE),
+            // without an enable file, the nearest we can get to restoring the fan is its speed
+            (ShutdownMode::Restore, None) => {
+                (&self.path_prefix, self.options.to_raw(self.initial_speed))
+            }
+            (ShutdownMode::Auto, None) | (ShutdownMode::Failsafe, _) => (
+                &self.path_prefix,
+                self.options.to_raw(self.options.failsafe_pwm),
+            ),
+        };
+        let res = self.sysfs.write(Path::new(path), &format!("{}\n", value));
         if let Err(e) = res {
             warn!(path_prefix = self.path_prefix.as_str(), error = ?e, "failed to reset fan");
         }
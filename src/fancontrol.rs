@@ -0,0 +1,170 @@
+//! Converts an lm-sensors `fancontrol` config into an equivalent whoosh config.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt::Write as _,
+    fs::read_to_string,
+    path::Path,
+};
+
+use crate::error::Error;
+
+/// A `hwmonN/pwmN`-style path from the config, split into the hwmon index and the file name.
+/// Older versions of fancontrol put `device/` in between, which is ignored.
+fn split_path(path: &str) -> Result<(usize, &str), Error> {
+    let invalid = || Error::InvalidFancontrol(format!("unrecognised path \"{}\"", path));
+    let (hwmon, file) = path.split_once('/').ok_or_else(invalid)?;
+    let file = file.strip_prefix("device/").unwrap_or(file);
+    let index = hwmon
+        .strip_prefix("hwmon")
+        .and_then(|index| index.parse().ok())
+        .ok_or_else(invalid)?;
+    Ok((index, file))
+}
+
+/// The number in a file name like `temp1_input`.
+fn file_index(file: &str, prefix: &str, suffix: &str) -> Result<usize, Error> {
+    file.strip_prefix(prefix)
+        .and_then(|file| file.strip_suffix(suffix))
+        .and_then(|index| index.parse().ok())
+        .ok_or_else(|| Error::InvalidFancontrol(format!("unrecognised file \"{}\"", file)))
+}
+
+/// Reads the fancontrol config at `path` and returns the whoosh config as TOML. Each fan gets a
+/// linear curve from `MINTEMP`/`MINSTOP` to `MAXTEMP`/`MAXPWM`, and is stopped below `MINTEMP` if
+/// `MINPWM` is 0.
+pub fn import(path: &Path) -> Result<String, Error> {
+    let contents = read_to_string(path).map_err(Error::file(path))?;
+    let mut interval = 10;
+    // maps each key to its `a=b` pairs
+    let mut settings: HashMap<&str, BTreeMap<&str, &str>> = HashMap::new();
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| Error::InvalidFancontrol(format!("unrecognised line \"{}\"", line)))?;
+        if key == "INTERVAL" {
+            interval = value.parse().map_err(|_| {
+                Error::InvalidFancontrol(format!("INTERVAL \"{}\" is not an integer", value))
+            })?;
+            continue;
+        }
+        let pairs = settings.entry(key).or_default();
+        for pair in value.split_whitespace() {
+            let (name, value) = pair.split_once('=').ok_or_else(|| {
+                Error::InvalidFancontrol(format!("unrecognised value \"{}\" for {}", pair, key))
+            })?;
+            pairs.insert(name, value);
+        }
+    }
+
+    let empty = BTreeMap::new();
+    let setting = |key: &str| settings.get(key).unwrap_or(&empty);
+    let mut hwmon_names = HashMap::new();
+    for (hwmon, name) in setting("DEVNAME") {
+        let index = hwmon
+            .strip_prefix("hwmon")
+            .and_then(|index| index.parse::<usize>().ok())
+            .ok_or_else(|| Error::InvalidFancontrol(format!("unrecognised hwmon \"{}\"", hwmon)))?;
+        hwmon_names.insert(index, *name);
+    }
+    let hwmon_name = |index: usize| {
+        hwmon_names
+            .get(&index)
+            .copied()
+            .ok_or_else(|| Error::InvalidFancontrol(format!("DEVNAME doesn't name hwmon{}", index)))
+    };
+    // fan settings are all keyed by the pwm path
+    let fan_setting = |key: &str, pwm: &str, default: &str| -> Result<u32, Error> {
+        let value = setting(key).get(pwm).copied().unwrap_or(default);
+        value
+            .parse()
+            .map_err(|_| Error::InvalidFancontrol(format!("{} for {} is \"{}\"", key, pwm, value)))
+    };
+
+    let mut sensors = BTreeSet::new();
+    let mut out = String::new();
+    let mut fans = String::new();
+    let mut curves = String::new();
+    for (pwm, temps) in setting("FCTEMPS") {
+        let (pwm_hwmon, pwm_file) = split_path(pwm)?;
+        let fan_name = format!("hwmon{}_{}", pwm_hwmon, pwm_file);
+        let mut inputs = Vec::new();
+        for temp in temps.split('+') {
+            let (temp_hwmon, temp_file) = split_path(temp)?;
+            let index = file_index(temp_file, "temp", "_input")?;
+            let sensor_name = format!("hwmon{}_temp{}", temp_hwmon, index);
+            sensors.insert((sensor_name.clone(), hwmon_name(temp_hwmon)?, index));
+            inputs.push(format!("\"{}\"", sensor_name));
+        }
+
+        let min_temp = fan_setting("MINTEMP", pwm, "")?;
+        let max_temp = fan_setting("MAXTEMP", pwm, "")?;
+        let min_start = fan_setting("MINSTART", pwm, "0")?;
+        let min_stop = fan_setting("MINSTOP", pwm, "0")?;
+        let min_pwm = fan_setting("MINPWM", pwm, "0")?;
+        let max_pwm = fan_setting("MAXPWM", pwm, "255")?;
+        let _ = writeln!(
+            curves,
+            "{} = [\"{}C/{}pwm\", \"{}C/{}pwm\"]",
+            fan_name, min_temp, min_stop, max_temp, max_pwm
+        );
+
+        let _ = writeln!(fans, "\n[fans.{}]", fan_name);
+        let tach = match setting("FCFANS").get(pwm) {
+            Some(fan) => {
+                let (_, fan_file) = split_path(fan)?;
+                format!(", tach_index = {}", file_index(fan_file, "fan", "_input")?)
+            }
+            None => String::new(),
+        };
+        let _ = writeln!(
+            fans,
+            "path = {{ hwmon_name = \"{}\", index = {}{} }}",
+            hwmon_name(pwm_hwmon)?,
+            file_index(pwm_file, "pwm", "")?,
+            tach
+        );
+        if inputs.len() == 1 {
+            let _ = writeln!(fans, "input = {}", inputs[0]);
+        } else {
+            let _ = writeln!(fans, "input = [{}]", inputs.join(", "));
+        }
+        let _ = writeln!(fans, "curve = \"{}\"", fan_name);
+        if min_pwm == 0 {
+            let _ = writeln!(fans, "stop_below = \"{}C\"", min_temp);
+        } else if min_pwm != min_stop {
+            let _ = writeln!(
+                fans,
+                "# fancontrol used MINPWM = {} below {}C, which isn't converted",
+                min_pwm, min_temp
+            );
+        }
+        if min_start > min_stop {
+            let _ = writeln!(fans, "spinup_pwm = {}", min_start);
+        }
+    }
+
+    let _ = writeln!(out, "# converted from {}", path.display());
+    let _ = writeln!(out, "poll_period = {}", interval * 1000);
+    // fancontrol doesn't limit how quickly fans change speed
+    let _ = writeln!(out, "min_change = 0");
+    let _ = writeln!(out, "max_change = 100");
+    let _ = writeln!(out, "\n[sensors]");
+    for (name, hwmon_name, index) in sensors.iter() {
+        let _ = writeln!(
+            out,
+            "{} = {{ hwmon_name = \"{}\", index = {} }}",
+            name, hwmon_name, index
+        );
+    }
+    let _ = writeln!(out, "\n[composites]");
+    let _ = writeln!(out, "\n[curves]");
+    out.push_str(&curves);
+    let _ = writeln!(out, "\n[fans]");
+    out.push_str(&fans);
+    Ok(out)
+}
@@ -0,0 +1,1542 @@
+//! The fan control loop and everything it's built from. The `whoosh` binary is a thin wrapper
+//! around `main_loop`.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io::Write,
+    path::Path,
+    process::Command,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use chrono::{Local, Timelike};
+use tracing::{
+    debug, debug_span, error,
+    field::{display, Empty},
+    info, info_span, warn,
+};
+
+use crate::{
+    args::Args,
+    config::{
+        BlendCombine, CompositeMode, Fan, FanCurve, FanMode, LabelCache, ShownTemp, TempUnit,
+    },
+    curve::Curve,
+    error::Error,
+    metrics::MetricsServer,
+    pid::PidController,
+    replay::Recorder,
+    sensor::{SensorHistory, SensorInput},
+    socket::StatusSocket,
+    status::{CompositeStatus, FanStatus, ProfileRequest, SharedStatus, Status},
+    sysfs::{RealSysfs, SharedSysfs, Sysfs},
+    timing::{Clock, PollTimer},
+};
+
+pub use crate::{
+    config::Config,
+    curve::{curve_lerp, Point},
+    fan::ControlledFan,
+};
+
+pub mod args;
+pub mod config;
+pub mod curve;
+pub mod error;
+pub mod fan;
+pub mod fancontrol;
+mod metrics;
+mod notify;
+#[cfg(feature = "nvml")]
+mod nvml;
+pub mod pid;
+pub mod replay;
+pub mod sensor;
+mod socket;
+pub mod status;
+pub mod sysfs;
+mod timing;
+
+/// The longest we sleep between scans while waiting for hwmons to appear.
+const MAX_STARTUP_BACKOFF_MS: u64 = 2000;
+/// How often a sleep between polls checks whether it should end early.
+const SLEEP_SLICE_MS: u64 = 100;
+/// How many consecutive polls a fan must read 0 RPM for before it is considered stalled.
+const STALL_POLLS: u32 = 3;
+/// The most threads that sensors are read on at once when `parallel_reads` is set.
+const MAX_READ_THREADS: usize = 8;
+
+pub struct State {
+    config: Config,
+    /// Where every sensor and fan is read and written.
+    sysfs: SharedSysfs,
+    /// The hwmons that were present when the state was built.
+    hwmon_names: BTreeMap<usize, String>,
+    sensor_inputs: HashMap<String, SensorInput>,
+    /// Sensor names, ordered so that every sensor comes after its fallback.
+    sensor_order: Vec<String>,
+    /// Composite names, ordered so that every composite comes after the composites it uses.
+    composite_order: Vec<String>,
+    fans: HashMap<String, ControlledFan>,
+    curves: HashMap<String, Curve>,
+    /// Curves defined directly on a fan, keyed by the fan's name.
+    inline_curves: HashMap<String, Curve>,
+    /// Each fan's deadband and ramp limit, scaled to 0-255.
+    change_limits: HashMap<String, ChangeLimits>,
+    /// The temperature each fan's speed was last calculated from, after hysteresis.
+    last_temps: HashMap<String, i32>,
+    /// If set, fan speeds are calculated and logged but never written.
+    dry_run: bool,
+    sensor_histories: HashMap<String, SensorHistory>,
+    /// When each fan last wanted to be at least as fast as it was.
+    last_demands: HashMap<String, Instant>,
+    /// When each fan's speed was last changed.
+    last_writes: HashMap<String, Instant>,
+    /// What `last_demands` and `last_writes` are measured against, and what pid mode integrates
+    /// over.
+    clock: Clock,
+    /// The speed last written to each fan, for `coalesce_writes`.
+    written_speeds: HashMap<String, u8>,
+    /// How many consecutive polls each fan has appeared stalled for.
+    stall_counts: HashMap<String, u32>,
+    /// How many consecutive polls each fan's speed has failed to be set in.
+    write_errors: HashMap<String, u32>,
+    /// The fans that hit `max_write_errors`, which are left alone until the next reload.
+    failed_fans: HashSet<String>,
+    /// The fans whose input was above their critical temperature last poll.
+    critical_fans: HashSet<String>,
+    /// The controllers of fans in pid mode.
+    pid_controllers: HashMap<String, PidController>,
+    /// The active profile, or `None` for the base config.
+    profile: Option<String>,
+    /// The profile the schedule last switched to, so that a profile switched to by hand is only
+    /// overridden the next time the schedule changes.
+    scheduled_profile: Option<Option<String>>,
+    /// The times of recent polls.
+    poll_timer: PollTimer,
+    /// How many more polls fans should soft-start for, including the next one.
+    soft_start_left: u32,
+    /// If set, fans go straight to their targets, ignoring the deadband, ramp limits and
+    /// soft-start, since with `--once` there is no next poll to get there in.
+    jump_to_target: bool,
+    problems: Problems,
+}
+
+struct ChangeLimits {
+    /// Changes in speed no bigger than this are ignored.
+    min: isize,
+    /// Changes in speed are capped to this much per poll.
+    max: isize,
+}
+
+/// Problems that can persist poll after poll, so that each is only logged when it starts and
+/// when it clears, rather than every poll.
+#[derive(Default)]
+struct Problems {
+    /// The problems seen in the last complete poll.
+    active: HashSet<String>,
+    /// The problems seen so far in this poll.
+    current: HashSet<String>,
+}
+
+impl Problems {
+    /// Records that `problem` happened this poll, returning whether it has just started and so
+    /// should be logged.
+    fn report(&mut self, problem: String) -> bool {
+        let started = !self.active.contains(&problem) && !self.current.contains(&problem);
+        self.current.insert(problem);
+        started
+    }
+
+    /// Logs every problem that has cleared since the last poll, and starts the next one.
+    fn end_poll(&mut self) {
+        for problem in self.active.difference(&self.current) {
+            info!(problem = problem.as_str(), "problem has cleared");
+        }
+        self.active = std::mem::take(&mut self.current);
+    }
+}
+
+/// Flags set by signal handlers for `main_loop` to act on.
+#[derive(Default)]
+pub struct Signals {
+    pub stop: Arc<AtomicBool>,
+    pub reload: Arc<AtomicBool>,
+    /// Set alongside `reload` when everything should be reread from scratch.
+    pub force_reload: Arc<AtomicBool>,
+    /// Set when the current state should be logged.
+    pub dump: Arc<AtomicBool>,
+}
+
+/// Servers that outlive `main_loop`, so that they aren't rebound every time it restarts.
+#[derive(Default)]
+pub struct Servers {
+    metrics: Option<MetricsServer>,
+    status_socket: Option<StatusSocket>,
+    profile_request: ProfileRequest,
+}
+
+impl Servers {
+    fn ensure_started(&mut self, config: &Config, status: &SharedStatus) -> Result<(), Error> {
+        MetricsServer::ensure_started(&mut self.metrics, config.metrics_addr.as_deref(), status)?;
+        StatusSocket::ensure_started(
+            &mut self.status_socket,
+            config.status_socket.as_deref(),
+            status,
+            &self.profile_request,
+        )?;
+        Ok(())
+    }
+}
+
+impl State {
+    /// Builds the state for `config`, taking any of `old_fans` that are still configured the
+    /// same way. The rest are left in `old_fans`, except for any that had to be released to reopen
+    /// their path, so that they can be taken back if this fails.
+    pub fn new(
+        config: Config,
+        sysfs: SharedSysfs,
+        dry_run: bool,
+        old_fans: &mut HashMap<String, ControlledFan>,
+        label_cache: &mut LabelCache,
+    ) -> Result<Self, error::Error> {
+        let span = info_span!("load");
+        let _guard = span.enter();
+
+        // otherwise a typo would leave a fan uncontrolled with nothing but a warning every poll
+        config.check_references()?;
+        let hwmon_names = wait_for_hwmons(&config, &*sysfs)?;
+        let sensor_inputs = config.find_sensors(&*sysfs, &hwmon_names, label_cache)?;
+        let sensor_order = config.sensor_order()?;
+        let composite_order = config.composite_order()?;
+        let curves = config.parse_curves(None)?;
+        // so that a broken profile is caught now rather than when it's switched to
+        for profile in config.profiles.keys() {
+            config.parse_curves(Some(profile))?;
+        }
+        let inline_curves = config.parse_inline_curves()?;
+        // last, so that nothing after it can fail and lose the fans
+        let fans = config.find_fans(&sysfs, &hwmon_names, dry_run, old_fans)?;
+        let change_limits = change_limits(&config, None);
+        let soft_start_left = config.soft_start_polls;
+
+        Ok(State {
+            config,
+            sysfs,
+            hwmon_names,
+            sensor_inputs,
+            sensor_order,
+            composite_order,
+            fans,
+            curves,
+            inline_curves,
+            change_limits,
+            last_temps: HashMap::new(),
+            dry_run,
+            sensor_histories: HashMap::new(),
+            last_demands: HashMap::new(),
+            last_writes: HashMap::new(),
+            stall_counts: HashMap::new(),
+            clock: Clock::Real,
+            written_speeds: HashMap::new(),
+            write_errors: HashMap::new(),
+            failed_fans: HashSet::new(),
+            critical_fans: HashSet::new(),
+            pid_controllers: HashMap::new(),
+            profile: None,
+            scheduled_profile: None,
+            poll_timer: PollTimer::default(),
+            soft_start_left,
+            jump_to_target: false,
+            problems: Problems::default(),
+        })
+    }
+
+    /// Switches to whichever profile the schedule says should be active now, if that has changed
+    /// since the last call.
+    fn apply_schedule(&mut self) -> Result<(), Error> {
+        if self.config.schedule.is_empty() {
+            return Ok(());
+        }
+        let now = Local::now();
+        let minute = now.hour() * 60 + now.minute();
+        let scheduled = self
+            .config
+            .scheduled_profile(minute)
+            .filter(|profile| *profile != "default")
+            .map(str::to_owned);
+        if self.scheduled_profile.as_ref() == Some(&scheduled) {
+            return Ok(());
+        }
+        debug!(?scheduled, "schedule has changed");
+        self.scheduled_profile = Some(scheduled.clone());
+        self.set_profile(scheduled)
+    }
+
+    /// Switches to `profile`, or back to the base config if `None`.
+    fn set_profile(&mut self, profile: Option<String>) -> Result<(), Error> {
+        if let Some(name) = &profile {
+            if !self.config.profiles.contains_key(name) {
+                return Err(Error::UnknownProfile(name.clone()));
+            }
+        }
+        self.curves = self.config.parse_curves(profile.as_deref())?;
+        self.change_limits = change_limits(&self.config, profile.as_deref());
+        info!(?profile, "switched profile");
+        self.profile = profile;
+        Ok(())
+    }
+
+    /// Reads every sensor, calculates composites, and sets every fan's speed, recording what
+    /// happened in `status`.
+    pub fn poll(&mut self, status: &mut Status) -> Result<(), Error> {
+        let sensors_start = Instant::now();
+        let mut temps =
+            HashMap::with_capacity(self.sensor_inputs.len() + self.config.composites.len());
+        let mut readings = if self.config.parallel_reads {
+            read_sensors_parallel(&*self.sysfs, &self.sensor_inputs)
+        } else {
+            HashMap::new()
+        };
+        let sysfs = &*self.sysfs;
+        let unit = self.config.display_unit;
+        for name in self.sensor_order.iter() {
+            let input = &self.sensor_inputs[name];
+            let span = debug_span!("reading sensor", name = name.as_str(), %input);
+            let _guard = span.enter();
+            let sensor = &self.config.sensors[name];
+            let history = self.sensor_histories.entry(name.clone()).or_default();
+            let reading = readings.remove(name).unwrap_or_else(|| input.read(sysfs));
+            let read_failed = reading.is_err();
+            let temp = match reading {
+                Ok(raw_temp) => {
+                    status.raw_sensors.insert(name.clone(), raw_temp);
+                    let adjusted_temp = sensor.calibrate(raw_temp);
+                    let temp = history.update(sensor, adjusted_temp);
+                    // keyed without the reading, so that it's one problem however it varies
+                    if temp.is_none()
+                        && self
+                            .problems
+                            .report(format!("sensor {}: implausible reading", name))
+                    {
+                        warn!(
+                            temp = %unit.show(adjusted_temp),
+                            "rejecting implausible reading"
+                        );
+                    }
+                    debug!(
+                        raw_temp,
+                        adjusted_temp = %unit.show(adjusted_temp),
+                        temp = ?temp.map(|temp| unit.show(temp)),
+                        "read temperature"
+                    );
+                    temp
+                }
+                Err(error) if self.config.strict && sensor.fallback.is_none() => {
+                    return Err(error.in_sensor(name))
+                }
+                Err(error) => {
+                    if self.problems.report(format!("sensor {}: {}", name, error)) {
+                        warn!(?error, "failed to read sensor");
+                    }
+                    None
+                }
+            };
+            let temp = match temp {
+                Some(v) => v,
+                None => {
+                    // fallbacks come earlier in sensor_order, so have already been read
+                    let fallback_temp = sensor
+                        .fallback
+                        .as_ref()
+                        .and_then(|fallback| temps.get(fallback).copied());
+                    // the last good reading stands in for a rejected one, but a sensor that can't
+                    // be read at all is left out rather than being held at its last value
+                    let last_temp = if read_failed {
+                        None
+                    } else {
+                        history.last_temp()
+                    };
+                    match fallback_temp.or(last_temp) {
+                        Some(v) => {
+                            debug!(temp = %unit.show(v), "using fallback value");
+                            v
+                        }
+                        None => continue,
+                    }
+                }
+            };
+            temps.insert(name.clone(), temp);
+            status.sensors.insert(name.clone(), temp);
+        }
+        status.poll_times.sensors = sensors_start.elapsed();
+        let composites_start = Instant::now();
+
+        for name in self.composite_order.iter() {
+            let composite = &self.config.composites[name];
+            let span = debug_span!("calculating composite", name = name.as_str());
+            let _guard = span.enter();
+            let mut inputs = Vec::with_capacity(composite.inputs.len());
+            let mut weights = Vec::with_capacity(composite.inputs.len());
+            for (i, input_name) in composite.inputs.iter().enumerate() {
+                match temps.get(input_name) {
+                    Some(v) => {
+                        inputs.push(*v);
+                        weights.push(composite.weight(i));
+                    }
+                    None => {
+                        let problem = format!("composite {}: input {} not found", name, input_name);
+                        if self.problems.report(problem) {
+                            warn!(name = input_name.as_str(), "input not found");
+                        }
+                        continue;
+                    }
+                }
+            }
+
+            if inputs.is_empty() {
+                if self
+                    .problems
+                    .report(format!("composite {}: no inputs", name))
+                {
+                    warn!("no inputs");
+                }
+                continue;
+            }
+
+            let pseudo_temp = match &composite.mode {
+                CompositeMode::Mean => mean(&inputs),
+                CompositeMode::Max => *inputs.iter().max().unwrap(),
+                CompositeMode::Min => *inputs.iter().min().unwrap(),
+                CompositeMode::WeightedMean { .. } => match weighted_mean(&inputs, &weights) {
+                    Some(v) => v,
+                    None => {
+                        let problem = format!("composite {}: total weight is zero", name);
+                        if self.problems.report(problem) {
+                            warn!("total weight of inputs is zero");
+                        }
+                        continue;
+                    }
+                },
+                CompositeMode::MeanMax { threshold } => {
+                    let max = *inputs.iter().max().unwrap();
+                    if max > *threshold {
+                        max
+                    } else {
+                        mean(&inputs)
+                    }
+                }
+            };
+            let inputs: BTreeMap<String, i32> = composite
+                .inputs
+                .iter()
+                .filter_map(|input_name| Some((input_name.clone(), *temps.get(input_name)?)))
+                .collect();
+            let shown_inputs: BTreeMap<&str, ShownTemp> = inputs
+                .iter()
+                .map(|(input_name, &temp)| (input_name.as_str(), unit.show(temp)))
+                .collect();
+            debug!(
+                pseudo_temp = %unit.show(pseudo_temp),
+                inputs = ?shown_inputs,
+                "calculated composite temperature"
+            );
+
+            temps.insert(name.clone(), pseudo_temp);
+            status.composites.insert(
+                name.clone(),
+                CompositeStatus {
+                    temp: pseudo_temp,
+                    inputs,
+                },
+            );
+        }
+        status.poll_times.composites = composites_start.elapsed();
+        let fans_start = Instant::now();
+
+        let fan_names: Vec<String> = self.config.fans.keys().cloned().collect();
+        for name in fan_names.iter() {
+            if self.failed_fans.contains(name) {
+                continue;
+            }
+            let error = match self.control_fan(name, &temps, status) {
+                Ok(()) => {
+                    self.write_errors.remove(name);
+                    continue;
+                }
+                Err(error) => error,
+            };
+            if self.config.strict {
+                return Err(error.in_fan(name));
+            }
+            if let Error::SpeedWrite { .. } = error {
+                let write_errors = self.write_errors.entry(name.clone()).or_insert(0);
+                *write_errors += 1;
+                let max_write_errors = self.config.fans[name].max_write_errors;
+                if max_write_errors.is_some_and(|max| *write_errors >= max) {
+                    error!(
+                        name = name.as_str(),
+                        ?error,
+                        write_errors = *write_errors,
+                        "too many failures to set fan speed - leaving fan alone until reload"
+                    );
+                    self.failed_fans.insert(name.clone());
+                    continue;
+                }
+            }
+            if self.problems.report(format!("fan {}: {}", name, error)) {
+                warn!(name = name.as_str(), ?error, "failed to control fan");
+            }
+        }
+        self.problems.end_poll();
+        status.poll_times.fans = fans_start.elapsed();
+        self.soft_start_left = self.soft_start_left.saturating_sub(1);
+        Ok(())
+    }
+
+    /// Calculates and sets the speed of the fan called `name`, using `temps` from this poll.
+    fn control_fan(
+        &mut self,
+        name: &String,
+        temps: &HashMap<String, i32>,
+        status: &mut Status,
+    ) -> Result<(), Error> {
+        let fan = &self.config.fans[name];
+        let span = debug_span!("controlling fan", name = name.as_str(), input = Empty);
+        let _guard = span.enter();
+        let mut input_temp = None;
+        for input_name in fan.inputs().iter() {
+            match temps.get(input_name) {
+                Some(&v) => input_temp = input_temp.max(Some(v)),
+                None => {
+                    let problem = format!("fan {}: input {} not found", name, input_name);
+                    if self.problems.report(problem) {
+                        warn!(input = input_name.as_str(), "input not found");
+                    }
+                }
+            }
+        }
+        let input_temp = match input_temp {
+            Some(v) => v,
+            None => {
+                if self.problems.report(format!("fan {}: no inputs", name)) {
+                    warn!("no inputs");
+                }
+                return Ok(());
+            }
+        };
+        let unit = self.config.display_unit;
+        span.record("input", &display(unit.show(input_temp)));
+        if fan.is_critical(input_temp) {
+            // straight to full speed - no hysteresis, deadband or ramp limits
+            if self.critical_fans.insert(name.clone()) {
+                error!(
+                    input_temp = %unit.show(input_temp),
+                    "input is above critical_temp - setting fan to full speed"
+                );
+                if let Some(command) = &self.config.critical_command {
+                    run_critical_command(command, name, input_temp);
+                }
+            }
+            let controlled_fan = self.fans.get(name).unwrap();
+            let current_speed = controlled_fan.get_speed()?;
+            status.fans.insert(
+                name.clone(),
+                FanStatus {
+                    input_temp,
+                    current_speed,
+                    target_speed: 255,
+                    rpm: read_rpm(controlled_fan),
+                },
+            );
+            return write_speed(
+                controlled_fan,
+                name,
+                255,
+                self.dry_run,
+                self.config.coalesce_writes,
+                &mut self.written_speeds,
+            );
+        }
+        if self.critical_fans.remove(name) {
+            info!(
+                input_temp = %unit.show(input_temp),
+                "input is no longer above critical_temp"
+            );
+        }
+        let effective_temp = apply_hysteresis(
+            input_temp,
+            self.last_temps.get(name).copied(),
+            fan.hysteresis,
+        );
+        self.last_temps.insert(name.clone(), effective_temp);
+        if effective_temp != input_temp {
+            debug!(
+                effective_temp = %unit.show(effective_temp),
+                "holding temperature for hysteresis"
+            );
+        }
+        if fan.should_stop(effective_temp) {
+            // checked before min_pwm is applied, so that it wins, and bypassing the deadband and
+            // ramp limits, or the fan might never quite stop
+            let controlled_fan = self.fans.get(name).unwrap();
+            let current_speed = controlled_fan.get_speed()?;
+            status.fans.insert(
+                name.clone(),
+                FanStatus {
+                    input_temp,
+                    current_speed,
+                    target_speed: 0,
+                    rpm: read_rpm(controlled_fan),
+                },
+            );
+            if current_speed != 0 && !in_dwell(fan, self.last_writes.get(name), self.clock.now()) {
+                debug!("input is below stop_below - stopping fan");
+                write_speed(
+                    controlled_fan,
+                    name,
+                    0,
+                    self.dry_run,
+                    self.config.coalesce_writes,
+                    &mut self.written_speeds,
+                )?;
+                self.last_writes.insert(name.clone(), self.clock.now());
+            }
+            return Ok(());
+        }
+
+        let demand = match fan.mode {
+            FanMode::Curve => {
+                let curve = match fan.curve.as_ref().unwrap() {
+                    FanCurve::Named(curve_name) => self.curve(curve_name)?,
+                    FanCurve::Inline(_) => &self.inline_curves[name],
+                };
+                fan.adjust_curve_speed(curve.speed_at(effective_temp))
+            }
+            FanMode::Pid => self
+                .pid_controllers
+                .entry(name.clone())
+                .or_default()
+                .update(fan.pid.as_ref().unwrap(), effective_temp, self.clock.now()),
+            FanMode::Blend => {
+                let blend = fan.blend.as_ref().unwrap();
+                let mut speeds = Vec::with_capacity(blend.sources.len());
+                let mut weights = Vec::with_capacity(blend.sources.len());
+                for source in blend.sources.iter() {
+                    match temps.get(&source.input) {
+                        Some(&temp) => {
+                            speeds.push(self.curve(&source.curve)?.speed_at(temp) as i32);
+                            weights.push(source.weight);
+                        }
+                        None => {
+                            let problem = format!("fan {}: input {} not found", name, source.input);
+                            if self.problems.report(problem) {
+                                warn!(input = source.input.as_str(), "input not found");
+                            }
+                        }
+                    }
+                }
+                let speed = match blend.combine {
+                    BlendCombine::WeightedMax => weighted_max(&speeds, &weights),
+                    BlendCombine::WeightedMean => weighted_mean(&speeds, &weights),
+                };
+                match speed {
+                    Some(speed) => fan.adjust_curve_speed(speed.clamp(0, 255) as u8),
+                    None => {
+                        if self
+                            .problems
+                            .report(format!("fan {}: no blend inputs", name))
+                        {
+                            warn!("no blend inputs, or only ones with zero weight");
+                        }
+                        return Ok(());
+                    }
+                }
+            }
+        };
+        let mut target_speed = fan.clamp_speed(fan.apply_gamma(demand));
+        let controlled_fan = self.fans.get(name).unwrap();
+        if fan.initial_floor {
+            target_speed = target_speed.max(controlled_fan.initial_speed());
+        }
+        debug!(demand, target_speed, "calculated target fan speed");
+
+        let current_speed = controlled_fan.get_speed()? as isize;
+        let rpm = read_rpm(controlled_fan);
+        status.fans.insert(
+            name.clone(),
+            FanStatus {
+                input_temp,
+                current_speed: current_speed as u8,
+                target_speed,
+                rpm,
+            },
+        );
+
+        if let Some(rpm) = rpm {
+            debug!(rpm, "read fan tachometer");
+            let stalled = rpm == 0
+                && fan
+                    .stall_pwm
+                    .is_some_and(|stall_pwm| current_speed > stall_pwm as isize);
+            let stall_count = self.stall_counts.entry(name.clone()).or_insert(0);
+            if stalled {
+                *stall_count += 1;
+            } else {
+                *stall_count = 0;
+            }
+            if *stall_count == STALL_POLLS {
+                warn!(current_speed, "fan appears to have stalled");
+            }
+            if *stall_count >= STALL_POLLS && fan.stall_restart {
+                debug!("setting stalled fan to full speed");
+                write_speed(
+                    controlled_fan,
+                    name,
+                    255,
+                    self.dry_run,
+                    self.config.coalesce_writes,
+                    &mut self.written_speeds,
+                )?;
+                return Ok(());
+            }
+        }
+        let now = self.clock.now();
+        if in_dwell(fan, self.last_writes.get(name), now) {
+            debug!("changed too recently - not changing speed");
+            return Ok(());
+        }
+        let mut delta = target_speed as isize - current_speed;
+        if delta >= 0 {
+            self.last_demands.insert(name.clone(), now);
+        } else if let (Some(delay), Some(last_demand)) =
+            (fan.rampdown_delay_ms, self.last_demands.get(name))
+        {
+            if now.duration_since(*last_demand) < Duration::from_millis(delay) {
+                debug!(delta, "delaying ramp down");
+                return Ok(());
+            }
+        }
+        let limits = if self.jump_to_target {
+            &ChangeLimits { min: 0, max: 255 }
+        } else {
+            &self.change_limits[name]
+        };
+        if !(delta > limits.min || delta < -limits.min) {
+            debug!(delta, "delta is too small - not changing speed");
+            return Ok(());
+        }
+        if self.soft_start_left > 0 && !self.jump_to_target {
+            // cover an equal share of what's left of the way each poll, but always make progress
+            let step = delta / self.soft_start_left as isize;
+            delta = if step == 0 { delta.signum() } else { step };
+            debug!(
+                delta,
+                polls_left = self.soft_start_left,
+                "soft-starting fan"
+            );
+        }
+        match delta.signum() {
+            1 => delta = delta.clamp(0, limits.max),
+            -1 => delta = delta.clamp(-limits.max, 0),
+            _ => unreachable!(),
+        }
+        debug!(delta, "changing speed");
+        if let (0, Some(spinup_pwm)) = (current_speed, fan.spinup_pwm) {
+            debug!(spinup_pwm, "spinning up stopped fan");
+            write_speed(
+                controlled_fan,
+                name,
+                spinup_pwm,
+                self.dry_run,
+                self.config.coalesce_writes,
+                &mut self.written_speeds,
+            )?;
+            // a simulated clock has no real hardware to wait for
+            if let Clock::Real = self.clock {
+                std::thread::sleep(Duration::from_millis(fan.spinup_ms));
+            }
+        }
+        write_speed(
+            controlled_fan,
+            name,
+            apply_delta(current_speed, delta),
+            self.dry_run,
+            self.config.coalesce_writes,
+            &mut self.written_speeds,
+        )?;
+        self.last_writes.insert(name.clone(), now);
+        Ok(())
+    }
+
+    /// Logs everything `status` says about the last poll, along with where each sensor and fan
+    /// is.
+    fn dump(&self, status: &Status) {
+        let unit = self.config.display_unit;
+        info!(profile = ?self.profile, "dumping state");
+        for (name, input) in self.sensor_inputs.iter() {
+            info!(
+                name = name.as_str(),
+                %input,
+                temp = ?status.sensors.get(name).map(|&temp| unit.show(temp)),
+                raw_temp = ?status.raw_sensors.get(name),
+                "sensor"
+            );
+        }
+        for (name, composite) in status.composites.iter() {
+            let inputs: BTreeMap<&str, ShownTemp> = composite
+                .inputs
+                .iter()
+                .map(|(input_name, &temp)| (input_name.as_str(), unit.show(temp)))
+                .collect();
+            info!(
+                name = name.as_str(),
+                temp = %unit.show(composite.temp),
+                ?inputs,
+                "composite"
+            );
+        }
+        for (name, fan) in self.fans.iter() {
+            let fan_status = status.fans.get(name);
+            info!(
+                name = name.as_str(),
+                path = fan.path(),
+                input_temp = ?fan_status.map(|fan| unit.show(fan.input_temp)),
+                current_speed = ?fan_status.map(|fan| fan.current_speed),
+                target_speed = ?fan_status.map(|fan| fan.target_speed),
+                rpm = ?fan_status.and_then(|fan| fan.rpm),
+                "fan"
+            );
+        }
+    }
+
+    /// Looks up a curve from `[curves]`, or from the active profile. Fans that name the same curve
+    /// share it.
+    fn curve(&self, name: &str) -> Result<&Curve, Error> {
+        self.curves
+            .get(name)
+            .ok_or_else(|| Error::CurveNotFound(name.to_owned()))
+    }
+
+    /// Sets every fan to the configured failsafe speed, so that nothing overheats while we're
+    /// not in control.
+    fn apply_failsafe(&self) {
+        for (name, fan) in self.fans.iter() {
+            let res = set_speed(fan, name, self.config.failsafe_pwm, self.dry_run);
+            if let Err(error) = res {
+                warn!(
+                    name = name.as_str(),
+                    ?error,
+                    "failed to set fan to failsafe speed"
+                );
+            }
+        }
+    }
+}
+
+/// Starts `command` in the background, logging how it exits.
+fn run_critical_command(command: &[String], fan: &str, temp: i32) {
+    let (program, args) = match command.split_first() {
+        Some(v) => v,
+        None => {
+            warn!("critical_command is empty");
+            return;
+        }
+    };
+    let child = Command::new(program)
+        .args(args)
+        .env("WHOOSH_FAN", fan)
+        .env("WHOOSH_TEMP", temp.to_string())
+        .spawn();
+    let mut child = match child {
+        Ok(v) => v,
+        Err(error) => {
+            error!(
+                program = program.as_str(),
+                ?error,
+                "failed to run critical_command"
+            );
+            return;
+        }
+    };
+    let program = program.clone();
+    // don't hold up the control loop while it runs
+    std::thread::spawn(move || match child.wait() {
+        Ok(status) if status.success() => {
+            info!(program = program.as_str(), "critical_command succeeded")
+        }
+        Ok(status) => warn!(program = program.as_str(), %status, "critical_command failed"),
+        Err(error) => warn!(
+            program = program.as_str(),
+            ?error,
+            "failed to wait for critical_command"
+        ),
+    });
+}
+
+/// Each fan's deadband and ramp limit while `profile` is active, preferring the fan's own
+/// overrides, then the profile's, then the global ones.
+fn change_limits(config: &Config, profile: Option<&str>) -> HashMap<String, ChangeLimits> {
+    let profile = profile.map(|profile| &config.profiles[profile]);
+    let min_change = profile
+        .and_then(|profile| profile.min_change)
+        .unwrap_or(config.min_change);
+    let max_change = profile
+        .and_then(|profile| profile.max_change)
+        .unwrap_or(config.max_change);
+    config
+        .fans
+        .iter()
+        .map(|(name, fan)| {
+            let limits = ChangeLimits {
+                min: fan.min_change.unwrap_or(min_change) as isize * 255 / 100,
+                max: fan.max_change.unwrap_or(max_change) as isize * 255 / 100,
+            };
+            (name.clone(), limits)
+        })
+        .collect()
+}
+
+/// Whether `fan`, last changed at `last_write`, is still within its `min_dwell_ms` at `now`.
+fn in_dwell(fan: &Fan, last_write: Option<&Instant>, now: Instant) -> bool {
+    match (fan.min_dwell_ms, last_write) {
+        (Some(dwell), Some(last_write)) => {
+            now.duration_since(*last_write) < Duration::from_millis(dwell)
+        }
+        _ => false,
+    }
+}
+
+/// `current` fan speed changed by `delta`, clamped to 0-255 so that it can never wrap around. With
+/// a sane current speed the delta already keeps it in range.
+fn apply_delta(current: isize, delta: isize) -> u8 {
+    let new_speed = current.saturating_add(delta);
+    if !(0..=255).contains(&new_speed) {
+        debug!(new_speed, "new speed is out of range - clamping");
+    }
+    new_speed.clamp(0, 255) as u8
+}
+
+/// Rising temperatures are followed immediately, but a falling temperature only moves the result
+/// once it has dropped more than `hysteresis` below the last result.
+fn apply_hysteresis(temp: i32, last: Option<i32>, hysteresis: i32) -> i32 {
+    match last {
+        Some(last) if temp < last => last.min(temp + hysteresis),
+        _ => temp,
+    }
+}
+
+/// Integer mean of `inputs`, rounded to nearest (halves away from zero). `inputs` must not be
+/// empty.
+fn mean(inputs: &[i32]) -> i32 {
+    let len = inputs.len() as i64;
+    let sum: i64 = inputs.iter().map(|&v| v as i64).sum();
+    let rounded = if sum < 0 {
+        sum - len / 2
+    } else {
+        sum + len / 2
+    };
+    (rounded / len) as i32
+}
+
+/// Mean of `inputs` weighted by the corresponding entries in `weights`, rounded to nearest.
+/// Returns `None` if the weights sum to zero.
+fn weighted_mean(inputs: &[i32], weights: &[f32]) -> Option<i32> {
+    let total_weight: f64 = weights.iter().map(|&w| w as f64).sum();
+    if total_weight == 0.0 {
+        return None;
+    }
+    let sum: f64 = inputs
+        .iter()
+        .zip(weights.iter())
+        .map(|(&v, &w)| v as f64 * w as f64)
+        .sum();
+    Some((sum / total_weight).round() as i32)
+}
+
+/// The highest of `inputs`, each multiplied by the corresponding entry in `weights` and rounded to
+/// nearest. Returns `None` if there are no inputs.
+fn weighted_max(inputs: &[i32], weights: &[f32]) -> Option<i32> {
+    inputs
+        .iter()
+        .zip(weights.iter())
+        .map(|(&v, &w)| (v as f64 * w as f64).round() as i32)
+        .max()
+}
+
+/// Takes a raw reading from every sensor, sharing them out between up to `MAX_READ_THREADS`
+/// threads. A sensor whose read panicked gets an error, the same as any other failed read.
+fn read_sensors_parallel<'a>(
+    sysfs: &dyn Sysfs,
+    inputs: &'a HashMap<String, SensorInput>,
+) -> HashMap<&'a String, Result<i32, Error>> {
+    let inputs: Vec<_> = inputs.iter().collect();
+    let next = AtomicUsize::new(0);
+    let readings = Mutex::new(HashMap::with_capacity(inputs.len()));
+    std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..inputs.len().min(MAX_READ_THREADS))
+            .map(|_| {
+                scope.spawn(|| {
+                    // each worker takes the next unread sensor until there are none left
+                    while let Some(&(name, input)) =
+                        inputs.get(next.fetch_add(1, Ordering::Relaxed))
+                    {
+                        let reading = input.read(sysfs);
+                        readings.lock().unwrap().insert(name, reading);
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            if worker.join().is_err() {
+                warn!("a sensor reading thread panicked");
+            }
+        }
+    });
+    // a worker panicking only poisons the lock, the other readings in it are still good
+    let mut readings = readings
+        .into_inner()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    for (name, _) in inputs {
+        readings
+            .entry(name)
+            .or_insert_with(|| Err(Error::ReadPanicked));
+    }
+    readings
+}
+
+/// Maps the index of each `/sys/class/hwmon/hwmonN` to its name. The indices aren't necessarily
+/// contiguous.
+fn hwmon_names(sysfs: &dyn Sysfs) -> Result<BTreeMap<usize, String>, Error> {
+    let mut hwmon_names = BTreeMap::new();
+    for entry in sysfs.read_dir(Path::new("/sys/class/hwmon"))? {
+        let index = match entry
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix("hwmon"))
+            .and_then(|index| index.parse().ok())
+        {
+            Some(v) => v,
+            None => continue,
+        };
+        // some hwmons have no name, but then they can't be referred to anyway
+        let name = match sysfs.read_to_string(&entry.join("name")) {
+            Ok(v) => v,
+            Err(error) => {
+                debug!(index, ?error, "skipping hwmon without a readable name");
+                continue;
+            }
+        };
+        hwmon_names.insert(index, name.trim_end().to_owned());
+    }
+    tracing::debug!("found hwmons: {:?}", hwmon_names);
+    Ok(hwmon_names)
+}
+
+/// Scans hwmons until every one the config refers to has appeared, or `startup_wait_ms` has
+/// passed, backing off between scans.
+fn wait_for_hwmons(config: &Config, sysfs: &dyn Sysfs) -> Result<BTreeMap<usize, String>, Error> {
+    if config.startup_wait_ms == 0 {
+        return hwmon_names(sysfs);
+    }
+    let deadline = Instant::now() + Duration::from_millis(config.startup_wait_ms);
+    let mut backoff = Duration::from_millis(100);
+    loop {
+        let hwmon_names = hwmon_names(sysfs)?;
+        let missing = config.missing_hwmons(sysfs, &hwmon_names);
+        let now = Instant::now();
+        if missing.is_empty() || now >= deadline {
+            return Ok(hwmon_names);
+        }
+        info!(?missing, "waiting for hwmons to appear");
+        std::thread::sleep(backoff.min(deadline - now));
+        backoff = (backoff * 2).min(Duration::from_millis(MAX_STARTUP_BACKOFF_MS));
+    }
+}
+
+/// Loads and fully validates the config without taking control of any fans, then prints a
+/// summary.
+pub fn check(args: &Args) -> Result<(), Error> {
+    let config = Config::load(&args.config_path)?;
+    config.check_references()?;
+    config.sensor_order()?;
+    config.composite_order()?;
+    let curves = config.parse_curves(None)?;
+    for profile in config.profiles.keys() {
+        config.parse_curves(Some(profile))?;
+    }
+    config.parse_inline_curves()?;
+    let sysfs: SharedSysfs = Arc::new(RealSysfs);
+    let hwmon_names = hwmon_names(&*sysfs)?;
+    let sensor_inputs = config.find_sensors(&*sysfs, &hwmon_names, &mut LabelCache::default())?;
+    let fans = config.find_fans(&sysfs, &hwmon_names, true, &mut HashMap::new())?;
+
+    println!("{}: ok", args.config_path.display());
+    for (name, input) in sensor_inputs.iter() {
+        println!("sensor {}: {}", name, input);
+    }
+    for name in config.composites.keys() {
+        println!("composite {}", name);
+    }
+    for name in curves.keys() {
+        println!("curve {}", name);
+    }
+    for (name, fan) in fans.iter() {
+        println!("fan {}: {}", name, fan.path());
+    }
+    Ok(())
+}
+
+/// Sleeps for `duration`, but wakes early if any of `flags` is set, so that signals are handled
+/// promptly.
+pub fn interruptible_sleep(duration: Duration, flags: &[&AtomicBool]) {
+    let deadline = Instant::now() + duration;
+    loop {
+        let now = Instant::now();
+        if now >= deadline || flags.iter().any(|flag| flag.load(Ordering::Relaxed)) {
+            return;
+        }
+        std::thread::sleep((deadline - now).min(Duration::from_millis(SLEEP_SLICE_MS)));
+    }
+}
+
+/// Prints the speed the curve called `name` gives at every whole degree between its first and
+/// last points, as CSV. If `fan` is given, its `stop_below`, `min_pwm` and `max_pwm` are applied.
+pub fn dump_curve(args: &Args, name: &str, fan: Option<&str>) -> Result<(), Error> {
+    let config = Config::load(&args.config_path)?;
+    let curves = config.parse_curves(None)?;
+    let curve = curves
+        .get(name)
+        .ok_or_else(|| Error::InvalidArgs(format!("no curve called \"{}\"", name)))?;
+    let fan = match fan {
+        Some(fan) => Some(
+            config
+                .fans
+                .get(fan)
+                .ok_or_else(|| Error::InvalidArgs(format!("no fan called \"{}\"", fan)))?,
+        ),
+        None => None,
+    };
+
+    let (first, last) = curve.temp_range();
+    // write rather than print, so that e.g. piping into head doesn't panic
+    let mut out = std::io::stdout().lock();
+    writeln!(out, "temp_c,pwm,percent")?;
+    // whole degrees only, rounding inwards so that every row is within the curve
+    for temp_c in (first as f64 / 1000.0).ceil() as i32..=(last as f64 / 1000.0).floor() as i32 {
+        let temp = temp_c * 1000;
+        let pwm = match fan {
+            Some(fan) => {
+                let speed = fan.apply_gamma(fan.adjust_curve_speed(curve.speed_at(temp)));
+                fan.limit_speed(temp, speed)
+            }
+            None => curve.speed_at(temp),
+        };
+        let percent = (pwm as u32 * 100 + 127) / 255;
+        writeln!(out, "{},{},{}", temp_c, pwm, percent)?;
+    }
+    Ok(())
+}
+
+/// Describes each fan's input and speed in one line, like `cpu_fan: 58C -> 140 (+12)`, where
+/// the number in brackets is how far the fan is from its target.
+fn summary(status: &Status) -> String {
+    let fans: Vec<String> = status
+        .fans
+        .iter()
+        .map(|(name, fan)| {
+            let input_temp = match status.display_unit {
+                // rounded down, below zero as well as above, since millidegrees are too noisy
+                // for a one-line summary
+                TempUnit::Millidegrees => format!("{}C", fan.input_temp.div_euclid(1000)),
+                unit => unit.show(fan.input_temp).to_string(),
+            };
+            format!(
+                "{}: {} -> {} ({:+})",
+                name,
+                input_temp,
+                fan.target_speed,
+                fan.target_speed as i32 - fan.current_speed as i32
+            )
+        })
+        .collect();
+    fans.join(", ")
+}
+
+/// Reads `fan`'s tachometer for the status, where a failure shouldn't stop the fan from being
+/// controlled.
+fn read_rpm(fan: &ControlledFan) -> Option<u32> {
+    fan.get_rpm().unwrap_or_else(|error| {
+        warn!(?error, "failed to read fan tachometer");
+        None
+    })
+}
+
+/// Sets `fan`'s speed, or just logs what it would have been set to in dry-run mode.
+fn set_speed(fan: &ControlledFan, name: &str, speed: u8, dry_run: bool) -> Result<(), Error> {
+    if dry_run {
+        info!("[dry-run] would set fan {} to {}", name, speed);
+        return Ok(());
+    }
+    fan.set_speed(speed)
+}
+
+/// Like `set_speed`, but records the speed in `written_speeds`, and if `coalesce` is set, skips
+/// the write when the speed is already what was last written.
+fn write_speed(
+    fan: &ControlledFan,
+    name: &str,
+    speed: u8,
+    dry_run: bool,
+    coalesce: bool,
+    written_speeds: &mut HashMap<String, u8>,
+) -> Result<(), Error> {
+    if coalesce && written_speeds.get(name) == Some(&speed) {
+        debug!(speed, "speed was already written - not writing again");
+        return Ok(());
+    }
+    set_speed(fan, name, speed, dry_run)?;
+    written_speeds.insert(name.to_owned(), speed);
+    Ok(())
+}
+
+pub fn main_loop(
+    args: &Args,
+    signals: &Signals,
+    status: &SharedStatus,
+    servers: &mut Servers,
+    started: &mut bool,
+) -> Result<(), Error> {
+    let config = Config::load(&args.config_path)?;
+    let mut label_cache = LabelCache::default();
+    let sysfs: SharedSysfs = Arc::new(RealSysfs);
+    let mut state = State::new(
+        config,
+        Arc::clone(&sysfs),
+        args.dry_run,
+        &mut HashMap::new(),
+        &mut label_cache,
+    )?;
+    state.jump_to_target = args.once;
+    servers.ensure_started(&state.config, status)?;
+    notify::notify("READY=1");
+    let mut recorder = args.record.as_deref().map(Recorder::open).transpose()?;
+    let watchdog_interval = notify::watchdog_interval();
+    if let Some(interval) = watchdog_interval {
+        debug!(?interval, "watchdog enabled");
+        if Duration::from_millis(state.config.poll_period) >= interval {
+            warn!(
+                ?interval,
+                "poll_period is longer than the watchdog interval"
+            );
+        }
+    }
+    while !signals.stop.load(Ordering::Relaxed) {
+        if state.config.rescan_hwmons {
+            match hwmon_names(&*sysfs) {
+                Ok(hwmon_names) if hwmon_names != state.hwmon_names => {
+                    info!("hwmons have changed");
+                    signals.reload.store(true, Ordering::Relaxed);
+                }
+                Ok(_) => {}
+                Err(error) => warn!(?error, "failed to rescan hwmons"),
+            }
+        }
+        if signals.force_reload.swap(false, Ordering::Relaxed) {
+            debug!("forgetting cached sensor labels");
+            label_cache.invalidate();
+        }
+        if signals.reload.load(Ordering::Relaxed) {
+            info!("attempting reload...");
+            notify::notify("RELOADING=1");
+            let new_config = match Config::load(&args.config_path) {
+                Ok(v) => v,
+                Err(error) => {
+                    error!(
+                        ?error,
+                        "failed to load new config - continuing with old one"
+                    );
+                    signals.reload.store(false, Ordering::Relaxed);
+                    notify::notify("READY=1");
+                    continue;
+                }
+            };
+            let old_config = state.config;
+            let old_profile = state.profile.take();
+            // so that a profile picked by hand still lasts until the schedule next changes
+            let scheduled_profile = state.scheduled_profile.take();
+            // fans whose config hasn't changed are kept open, so they don't blip
+            let mut old_fans = std::mem::take(&mut state.fans);
+            let poll_timer = std::mem::take(&mut state.poll_timer);
+            // a reload isn't a startup, so don't soft-start again
+            let soft_start_left = state.soft_start_left;
+            match State::new(
+                new_config,
+                Arc::clone(&sysfs),
+                args.dry_run,
+                &mut old_fans,
+                &mut label_cache,
+            ) {
+                Ok(new_state) => state = new_state,
+                Err(e) => {
+                    error!(?e, "failed to reload state - loading state from old config");
+                    // the old fans are still open, so the old config takes them straight back
+                    state = State::new(
+                        old_config,
+                        Arc::clone(&sysfs),
+                        args.dry_run,
+                        &mut old_fans,
+                        &mut label_cache,
+                    )?;
+                }
+            };
+            // only released now that the new state is known to be good
+            drop(old_fans);
+            state.poll_timer = poll_timer;
+            state.soft_start_left = soft_start_left;
+            state.jump_to_target = args.once;
+            state.scheduled_profile = scheduled_profile;
+            if old_profile.is_some() {
+                if let Err(error) = state.set_profile(old_profile) {
+                    warn!(?error, "failed to keep profile across reload");
+                }
+            }
+            signals.reload.store(false, Ordering::Relaxed);
+            servers.ensure_started(&state.config, status)?;
+            notify::notify("READY=1");
+        }
+
+        if let Err(error) = state.apply_schedule() {
+            warn!(?error, "failed to switch to scheduled profile");
+        }
+        let requested_profile = servers.profile_request.lock().unwrap().take();
+        if let Some(profile) = requested_profile {
+            let profile = Some(profile).filter(|profile| profile != "default");
+            if let Err(error) = state.set_profile(profile) {
+                warn!(?error, "failed to switch profile");
+            }
+        }
+
+        let poll_start = Instant::now();
+        let mut new_status = Status {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |t| t.as_millis() as u64),
+            profile: state.profile.clone(),
+            display_unit: state.config.display_unit,
+            ..Status::default()
+        };
+
+        if let Err(e) = state.poll(&mut new_status) {
+            // the fans are about to be released, but make sure they're left somewhere safe
+            state.apply_failsafe();
+            return Err(e);
+        }
+        // only now has it worked, so a first poll that always fails keeps backing off
+        *started = true;
+
+        new_status.poll_duration = poll_start.elapsed();
+        let log_timing = state
+            .poll_timer
+            .add(new_status.poll_duration, new_status.poll_times);
+        new_status.timing = state.poll_timer.stats();
+        if log_timing {
+            let total = new_status.timing.total;
+            info!(
+                min = ?total.min,
+                mean = ?total.mean,
+                max = ?total.max,
+                sensors_max = ?new_status.timing.sensors.max,
+                fans_max = ?new_status.timing.fans.max,
+                "poll times over the last {} polls",
+                timing::WINDOW
+            );
+        }
+        if state.config.log_summary {
+            info!("{}", summary(&new_status));
+        }
+        if let Some(recorder) = &mut recorder {
+            if let Err(error) = recorder.record(&new_status) {
+                warn!(?error, "failed to record poll");
+            }
+        }
+        if let Some(path) = &args.status_json {
+            if let Err(error) = new_status.write_json(path) {
+                warn!(?error, "failed to write status json");
+            }
+        }
+        if signals.dump.swap(false, Ordering::Relaxed) {
+            state.dump(&new_status);
+        }
+        *status.lock().unwrap() = new_status;
+
+        // only after a complete poll, so that a wedged read trips the watchdog
+        if watchdog_interval.is_some() {
+            notify::notify("WATCHDOG=1");
+        }
+        if args.once {
+            break;
+        }
+
+        interruptible_sleep(
+            Duration::from_millis(state.config.poll_period),
+            &[&signals.stop, &signals.reload],
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sysfs::FakeSysfs;
+
+    const SENSOR: &str = "/sys/class/hwmon/hwmon0/temp1_input";
+    const PWM: &str = "/sys/class/hwmon/hwmon0/pwm1";
+
+    /// A state for `config`, which should have one sensor and one fan on hwmon "x", reading from
+    /// and writing to a fake sysfs.
+    fn fake_state(config: &str) -> (State, Arc<FakeSysfs>) {
+        let config: Config = toml::from_str(config).unwrap();
+        let sysfs = Arc::new(config.fake_sysfs().unwrap());
+        let state = State::new(
+            config,
+            Arc::clone(&sysfs) as _,
+            false,
+            &mut HashMap::new(),
+            &mut LabelCache::default(),
+        )
+        .unwrap();
+        (state, sysfs)
+    }
+
+    /// Polls once with the sensor reading `temp` millidegrees, returning what the fan was set to.
+    fn poll_at(state: &mut State, sysfs: &FakeSysfs, temp: i32) -> u8 {
+        sysfs.insert(SENSOR, format!("{}\n", temp));
+        state.poll(&mut Status::default()).unwrap();
+        sysfs.get(PWM).unwrap().trim().parse().unwrap()
+    }
+
+    const ONE_FAN: &str = r#"
+        [sensors.cpu]
+        hwmon_name = "x"
+        index = 1
+        [composites]
+        [curves]
+        cpu = ["30C/0%", "60C/100%"]
+        [fans.f]
+        path = { hwmon_name = "x", index = 1 }
+        input = "cpu"
+        curve = "cpu"
+    "#;
+
+    #[test]
+    fn fake_sysfs_drives_a_fan() {
+        let (mut state, sysfs) = fake_state(ONE_FAN);
+        assert_eq!(
+            sysfs
+                .get("/sys/class/hwmon/hwmon0/pwm1_enable")
+                .unwrap()
+                .trim(),
+            "1"
+        );
+        assert_eq!(poll_at(&mut state, &sysfs, 45000), 127);
+        assert_eq!(poll_at(&mut state, &sysfs, 60000), 255);
+        assert_eq!(poll_at(&mut state, &sysfs, 20000), 0);
+    }
+
+    #[test]
+    fn stop_below_wins_over_min_pwm() {
+        let config = ONE_FAN.replace(
+            r#"curve = "cpu""#,
+            r#"curve = ["40C/0%", "80C/100%"]
+            min_pwm = 20
+            stop_below = "40C""#,
+        );
+        let (mut state, sysfs) = fake_state(&config);
+        assert_eq!(poll_at(&mut state, &sysfs, 30000), 0);
+        assert_eq!(poll_at(&mut state, &sysfs, 39999), 0);
+        // at exactly stop_below the fan runs, at no less than min_pwm
+        assert_eq!(poll_at(&mut state, &sysfs, 40000), 51);
+        assert_eq!(poll_at(&mut state, &sysfs, 39999), 0);
+        assert_eq!(poll_at(&mut state, &sysfs, 60000), 127);
+    }
+
+    #[test]
+    fn apply_delta_does_not_wrap() {
+        assert_eq!(apply_delta(100, 27), 127);
+        assert_eq!(apply_delta(100, -100), 0);
+        assert_eq!(apply_delta(250, 20), 255);
+        assert_eq!(apply_delta(3, -10), 0);
+        assert_eq!(apply_delta(300, 0), 255);
+        assert_eq!(apply_delta(isize::MAX, isize::MAX), 255);
+        assert_eq!(apply_delta(isize::MIN, -1), 0);
+    }
+
+    #[test]
+    fn full_speed_only_above_critical_temp() {
+        let config = ONE_FAN.replace(
+            r#"curve = "cpu""#,
+            r#"curve = "cpu"
+            critical_temp = "50C""#,
+        );
+        let (mut state, sysfs) = fake_state(&config);
+        assert_eq!(poll_at(&mut state, &sysfs, 50000), 170);
+        assert_eq!(poll_at(&mut state, &sysfs, 50001), 255);
+        // back to the curve straight away once it's no longer above
+        assert_eq!(poll_at(&mut state, &sysfs, 50000), 170);
+    }
+
+    #[test]
+    fn implausible_reading_is_one_problem() {
+        let config = ONE_FAN.replace("index = 1\n", "index = 1\nmax_valid = \"100C\"\n");
+        let (mut state, sysfs) = fake_state(&config);
+        let problem = "sensor cpu: implausible reading";
+        assert_eq!(poll_at(&mut state, &sysfs, 45000), 127);
+        // the last good reading stands in
+        assert_eq!(poll_at(&mut state, &sysfs, 150000), 127);
+        assert!(state.problems.active.contains(problem));
+        assert_eq!(poll_at(&mut state, &sysfs, 160000), 127);
+        assert!(state.problems.active.contains(problem));
+        assert_eq!(poll_at(&mut state, &sysfs, 60000), 255);
+        assert!(state.problems.active.is_empty());
+    }
+
+    #[test]
+    fn summary_rounds_down_below_zero() {
+        let mut status = Status::default();
+        for (name, input_temp) in [("a", -500), ("b", -1000), ("c", 1500)] {
+            let fan = FanStatus {
+                input_temp,
+                current_speed: 100,
+                target_speed: 90,
+                rpm: None,
+            };
+            status.fans.insert(name.to_owned(), fan);
+        }
+        assert_eq!(
+            summary(&status),
+            "a: -1C -> 90 (-10), b: -1C -> 90 (-10), c: 1C -> 90 (-10)"
+        );
+    }
+
+    #[test]
+    fn mean_rounds_to_nearest() {
+        assert_eq!(mean(&[40000]), 40000);
+        assert_eq!(mean(&[1, 2, 3]), 2);
+        // halves go away from zero
+        assert_eq!(mean(&[1, 2]), 2);
+        assert_eq!(mean(&[-1, -2]), -2);
+        assert_eq!(mean(&[1, 1, 2]), 1);
+    }
+
+    #[test]
+    fn mean_does_not_overflow() {
+        assert_eq!(mean(&[i32::MAX, i32::MAX]), i32::MAX);
+        assert_eq!(mean(&[i32::MIN, i32::MIN, i32::MIN]), i32::MIN);
+    }
+}
@@ -1,236 +1,130 @@
 use std::{
-    collections::HashMap,
-    fs::{read_dir, read_to_string},
-    path::PathBuf,
-    sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
-    },
-    time::Duration,
+    fs::OpenOptions,
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, Instant},
 };
 
-use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM, SIGUSR1};
-use tracing::{debug, debug_span, error, field::Empty, info, info_span, trace, trace_span, warn};
-use tracing_subscriber::EnvFilter;
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM, SIGUSR1, SIGUSR2};
+use tracing::{error, info};
+use tracing_subscriber::{fmt::writer::BoxMakeWriter, EnvFilter};
 
-use crate::{
-    config::{CompositeMode, Config},
+use whoosh::{
+    args::{Args, LogFormat, GIVE_UP_EXIT_CODE},
+    check, dump_curve,
     error::Error,
+    fan, fancontrol, interruptible_sleep, main_loop, replay,
+    status::SharedStatus,
+    Servers, Signals,
 };
 
-mod config;
-mod error;
-mod fan;
-
-const RETRY_MS: u64 = 2000;
-
-struct State {
-    config: Config,
-    sensor_paths: HashMap<String, PathBuf>,
-    fans: HashMap<String, fan::ControlledFan>,
-    curves: HashMap<String, Vec<Point>>,
-    min_change: isize,
-    max_change: isize,
-}
-
-#[derive(Debug)]
-struct Point {
-    temp: i32,
-    fan_speed: u8,
-}
-
-impl State {
-    fn new(config: Config) -> Result<Self, error::Error> {
-        let span = info_span!("load");
-        let _guard = span.enter();
-
-        let n_hwmons = read_dir("/sys/class/hwmon")?.count();
-        let mut hwmon_names = (0..n_hwmons)
-            .map(|n| read_to_string(format!("/sys/class/hwmon/hwmon{}/name", n)))
-            .collect::<Result<Vec<_>, _>>()?;
-        for name in hwmon_names.iter_mut() {
-            name.truncate(name.len() - 1);
-        }
-        tracing::debug!("found hwmons: {:?}", hwmon_names);
-
-        let sensor_paths = config.find_sensors(&hwmon_names)?;
-        let fans = config.find_fans(&hwmon_names)?;
-        let curves = config.parse_curves()?;
-        let min_change = config.min_change as isize * 255 / 100;
-        let max_change = config.max_change as isize * 255 / 100;
-
-        Ok(State {
-            config,
-            sensor_paths,
-            fans,
-            curves,
-            min_change,
-            max_change,
-        })
+fn main() {
+    // print the message itself, rather than the debug form `main` returning an error would
+    if let Err(error) = run() {
+        eprintln!("error: {}", error);
+        std::process::exit(1);
     }
 }
 
-fn curve_lerp(temp: i32, curve: &[Point]) -> u8 {
-    let span = trace_span!("curve lerp");
-    let _guard = span.enter();
-    if temp < curve[0].temp {
-        return curve[0].fan_speed;
+fn run() -> Result<(), Error> {
+    let args = Args::parse()?;
+    init_logging(&args)?;
+    if args.check {
+        return check(&args);
     }
-    for window in curve.windows(2) {
-        let (lower, upper) = (&window[0], &window[1]);
-        if temp >= lower.temp && temp < upper.temp {
-            trace!(?lower, ?upper, "temp in window");
-            let normalised_temp = (temp - lower.temp) as isize;
-            let upscale_factor = (upper.fan_speed - lower.fan_speed) as isize;
-            let downscale_factor = (upper.temp - lower.temp) as isize;
-            let fan_speed =
-                normalised_temp * upscale_factor / downscale_factor + lower.fan_speed as isize;
-            return fan_speed as u8;
-        }
+    if let Some(path) = &args.import_fancontrol {
+        print!("{}", fancontrol::import(path)?);
+        return Ok(());
     }
-    return curve[curve.len() - 1].fan_speed;
-}
-
-fn main_loop(stop: Arc<AtomicBool>, reload: Arc<AtomicBool>) -> Result<(), Error> {
-    let config = Config::load()?;
-    let mut state = State::new(config)?;
-    while !stop.load(Ordering::Relaxed) {
-        if reload.load(Ordering::Relaxed) {
-            info!("attempting reload...");
-            let new_config = match Config::load() {
-                Ok(v) => v,
-                Err(error) => {
-                    error!(
-                        ?error,
-                        "failed to load new config - continuing with old one"
-                    );
-                    continue;
-                }
-            };
-            let old_config = state.config;
-            // reset fans - we don't know the new config works, but can't have the same fan open
-            // twice
-            state.fans = HashMap::new();
-            match State::new(new_config) {
-                Ok(new_state) => state = new_state,
-                Err(e) => {
-                    error!(?e, "failed to reload state - loading state from old config");
-                    state = State::new(old_config)?;
-                }
-            };
-            reload.store(false, Ordering::Relaxed);
-        }
-
-        let mut temps =
-            HashMap::with_capacity(state.sensor_paths.len() + state.config.composites.len());
-        for (name, path) in state.sensor_paths.iter() {
-            let span = debug_span!(
-                "reading sensor",
-                name = name.as_str(),
-                path = path.to_str().unwrap()
-            );
-            let _guard = span.enter();
-            let temp: i32 = read_to_string(&path)?
-                .trim()
-                .parse()
-                .map_err(Error::InvalidReading)?;
-            debug!(temp, "read temperature");
-            temps.insert(name, temp);
-        }
-
-        for (name, composite) in state.config.composites.iter() {
-            let span = debug_span!("calculating composite", name = name.as_str());
-            let _guard = span.enter();
-            let mut inputs = Vec::with_capacity(composite.inputs.len());
-            for input_name in composite.inputs.iter() {
-                match temps.get(&input_name) {
-                    Some(v) => inputs.push(*v),
-                    None => {
-                        warn!(name = input_name.as_str(), "input not found");
-                        continue;
-                    }
-                }
-            }
+    if let Some(path) = &args.replay {
+        return replay::replay(&args, path);
+    }
+    if let Some(name) = &args.dump_curve {
+        return dump_curve(&args, name, args.fan.as_deref());
+    }
+    fan::install_panic_hook();
+    info!("hello!");
 
-            if inputs.len() == 0 {
-                warn!("no inputs");
-                continue;
+    let signals = Signals::default();
+    signal_hook::flag::register(SIGTERM, Arc::clone(&signals.stop))?;
+    signal_hook::flag::register(SIGINT, Arc::clone(&signals.stop))?;
+    signal_hook::flag::register(SIGHUP, Arc::clone(&signals.reload))?;
+    signal_hook::flag::register(SIGUSR1, Arc::clone(&signals.reload))?;
+    // SIGUSR1 also rereads everything from scratch, in case something changed that we can't see
+    signal_hook::flag::register(SIGUSR1, Arc::clone(&signals.force_reload))?;
+    signal_hook::flag::register(SIGUSR2, Arc::clone(&signals.dump))?;
+
+    let status = SharedStatus::default();
+    let mut servers = Servers::default();
+    let mut retry_ms = args.retry_ms;
+    // how many times in a row the main loop has failed without completing a poll, and since when
+    let mut failures = 0;
+    let mut failing_since = None;
+    while !signals.stop.load(Ordering::Relaxed) {
+        let mut started = false;
+        match main_loop(&args, &signals, &status, &mut servers, &mut started) {
+            Ok(()) => break,
+            // with --once, there's nothing to come back for
+            Err(e) if e.is_fatal() || args.once => {
+                error!("encountered fatal error in main loop:\n{}", e);
+                return Err(e);
             }
-
-            let pseudo_temp = match &composite.mode {
-                CompositeMode::Max => inputs.iter().max().unwrap(),
-                _ => todo!(),
-            };
-
-            temps.insert(&name, *pseudo_temp);
-        }
-
-        for (name, fan) in state.config.fans.iter() {
-            let span = debug_span!("controlling fan", name = name.as_str(), input = Empty);
-            let _guard = span.enter();
-            let input_temp = match temps.get(&fan.input) {
-                Some(v) => v,
-                None => {
-                    warn!(input = fan.input.as_str(), "input not found");
-                    continue;
+            Err(e) => {
+                error!("encountered error in main loop:\n{}", e);
+                // back off while the config or hardware is persistently broken, but start again
+                // from the shortest wait once it has worked
+                if started {
+                    retry_ms = args.retry_ms;
+                    failures = 0;
+                    failing_since = None;
                 }
-            };
-            span.record("input", input_temp);
-            let curve = match state.curves.get(&fan.curve) {
-                Some(v) => v,
-                None => {
-                    warn!(curve = fan.curve.as_str(), "curve not found");
-                    continue;
+                failures += 1;
+                let failing_for = failing_since.get_or_insert_with(Instant::now).elapsed();
+                if args.give_up_after.is_some_and(|limit| failures >= limit)
+                    || args
+                        .give_up_ms
+                        .is_some_and(|ms| failing_for >= Duration::from_millis(ms))
+                {
+                    error!(
+                        failures,
+                        failing_for_ms = failing_for.as_millis() as u64,
+                        "main loop keeps failing - giving up"
+                    );
+                    // nothing else will run, so close the servers now
+                    drop(servers);
+                    std::process::exit(GIVE_UP_EXIT_CODE);
                 }
-            };
-            let target_speed = curve_lerp(*input_temp, curve);
-            debug!(target_speed, "calculated target fan speed");
-
-            let fan = state.fans.get(name).unwrap();
-            let current_speed = fan.get_speed()? as isize;
-            let mut delta = target_speed as isize - current_speed;
-            if !(delta > state.min_change || delta < -state.min_change) {
-                debug!(delta, "delta is too small - not changing speed");
-                continue;
+                info!("waiting {}ms and reloading", retry_ms);
+                interruptible_sleep(Duration::from_millis(retry_ms), &[&signals.stop]);
+                retry_ms = retry_ms.saturating_mul(2).min(args.max_retry_ms);
             }
-            match delta.signum() {
-                1 => delta = delta.clamp(0, state.max_change),
-                -1 => delta = delta.clamp(-state.max_change, 0),
-                _ => unreachable!(),
-            }
-            debug!(delta, "changing speed");
-            fan.set_speed((current_speed + delta) as u8)?;
         }
-
-        std::thread::sleep(Duration::from_millis(state.config.poll_period));
     }
+
+    info!("shutting down...");
     Ok(())
 }
 
-fn main() -> Result<(), Error> {
-    tracing_subscriber::fmt::fmt()
-        .with_env_filter(EnvFilter::from_default_env())
-        .init();
-    info!("hello!");
-
-    let stop = Arc::new(AtomicBool::new(false));
-    signal_hook::flag::register(SIGTERM, Arc::clone(&stop))?;
-    signal_hook::flag::register(SIGINT, Arc::clone(&stop))?;
-    let reload = Arc::new(AtomicBool::new(false));
-    signal_hook::flag::register(SIGHUP, Arc::clone(&reload))?;
-    signal_hook::flag::register(SIGUSR1, Arc::clone(&reload))?;
-
-    while !stop.load(Ordering::Relaxed) {
-        match main_loop(Arc::clone(&stop), Arc::clone(&reload)) {
-            Ok(()) => break,
-            Err(e) => {
-                error!("encountered error in main loop:\n{}", e);
-                info!("waiting {}ms and reloading", RETRY_MS);
-                std::thread::sleep(Duration::from_millis(RETRY_MS));
-            }
+/// Sets up logging to stderr or `--log-file`, in the format from `--log-format`.
+fn init_logging(args: &Args) -> Result<(), Error> {
+    let writer = match &args.log_file {
+        Some(path) => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(Error::file(path))?;
+            BoxMakeWriter::new(Arc::new(file))
         }
+        None => BoxMakeWriter::new(std::io::stderr),
+    };
+    let builder = tracing_subscriber::fmt::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .with_writer(writer)
+        // colours would end up as escape codes in the file
+        .with_ansi(args.log_file.is_none());
+    match args.log_format {
+        LogFormat::Plain => builder.init(),
+        LogFormat::Json => builder.json().init(),
     }
-
-    info!("shutting down...");
     Ok(())
 }
@@ -0,0 +1,148 @@
+//! Prometheus metrics over HTTP.
+
+use std::{
+    fmt::Write as _,
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    thread,
+    time::Duration,
+};
+
+use tracing::{debug, info, warn};
+
+use crate::{
+    error::Error,
+    status::{SharedStatus, Status},
+};
+
+pub struct MetricsServer {
+    addr: String,
+}
+
+impl MetricsServer {
+    /// Binds to `addr` and serves metrics from `status` on a background thread.
+    pub fn start(addr: &str, status: SharedStatus) -> Result<Self, Error> {
+        let listener = TcpListener::bind(addr)?;
+        info!(addr, "serving metrics");
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let res = stream.and_then(|stream| handle(stream, &status));
+                if let Err(error) = res {
+                    debug!(?error, "failed to serve metrics request");
+                }
+            }
+        });
+        Ok(Self {
+            addr: addr.to_owned(),
+        })
+    }
+
+    /// Starts a server if `addr` is set and one isn't already running. The server lives as long as
+    /// the process, so a changed address only takes effect on restart.
+    pub fn ensure_started(
+        server: &mut Option<Self>,
+        addr: Option<&str>,
+        status: &SharedStatus,
+    ) -> Result<(), Error> {
+        match (server.as_ref(), addr) {
+            (None, Some(addr)) => *server = Some(Self::start(addr, SharedStatus::clone(status))?),
+            (Some(server), Some(addr)) if server.addr != addr => {
+                warn!(
+                    old = server.addr.as_str(),
+                    new = addr,
+                    "metrics_addr changed - restart to apply"
+                );
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+fn handle(mut stream: TcpStream, status: &SharedStatus) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut buf = [0; 1024];
+    let len = stream.read(&mut buf)?;
+    let request = String::from_utf8_lossy(&buf[..len]);
+    let path = request.split_whitespace().nth(1).unwrap_or("");
+
+    if path != "/metrics" {
+        stream.write_all(b"HTTP/1.0 404 Not Found\r\nContent-Length: 0\r\n\r\n")?;
+        return Ok(());
+    }
+
+    let body = render(&status.lock().unwrap());
+    write!(
+        stream,
+        "HTTP/1.0 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\r\n",
+        body.len()
+    )?;
+    stream.write_all(body.as_bytes())
+}
+
+fn render(status: &Status) -> String {
+    let mut out = String::new();
+    out.push_str("# HELP whoosh_temperature_celsius Latest sensor reading.\n");
+    out.push_str("# TYPE whoosh_temperature_celsius gauge\n");
+    for (name, temp) in status.sensors.iter() {
+        let _ = writeln!(
+            out,
+            "whoosh_temperature_celsius{{sensor=\"{}\"}} {}",
+            escape(name),
+            *temp as f64 / 1000.0
+        );
+    }
+    out.push_str("# HELP whoosh_composite_temperature_celsius Latest composite value.\n");
+    out.push_str("# TYPE whoosh_composite_temperature_celsius gauge\n");
+    for (name, composite) in status.composites.iter() {
+        let _ = writeln!(
+            out,
+            "whoosh_composite_temperature_celsius{{composite=\"{}\"}} {}",
+            escape(name),
+            composite.temp as f64 / 1000.0
+        );
+    }
+    out.push_str("# HELP whoosh_fan_pwm PWM value read from the fan.\n");
+    out.push_str("# TYPE whoosh_fan_pwm gauge\n");
+    for (name, fan) in status.fans.iter() {
+        let _ = writeln!(
+            out,
+            "whoosh_fan_pwm{{fan=\"{}\"}} {}",
+            escape(name),
+            fan.current_speed
+        );
+    }
+    out.push_str("# HELP whoosh_fan_target_pwm PWM value the fan is heading towards.\n");
+    out.push_str("# TYPE whoosh_fan_target_pwm gauge\n");
+    for (name, fan) in status.fans.iter() {
+        let _ = writeln!(
+            out,
+            "whoosh_fan_target_pwm{{fan=\"{}\"}} {}",
+            escape(name),
+            fan.target_speed
+        );
+    }
+    out.push_str("# HELP whoosh_fan_rpm Speed measured by the fan's tachometer.\n");
+    out.push_str("# TYPE whoosh_fan_rpm gauge\n");
+    for (name, fan) in status.fans.iter() {
+        if let Some(rpm) = fan.rpm {
+            let _ = writeln!(out, "whoosh_fan_rpm{{fan=\"{}\"}} {}", escape(name), rpm);
+        }
+    }
+    out.push_str("# HELP whoosh_poll_duration_seconds How long the latest poll took.\n");
+    out.push_str("# TYPE whoosh_poll_duration_seconds gauge\n");
+    let _ = writeln!(
+        out,
+        "whoosh_poll_duration_seconds {}",
+        status.poll_duration.as_secs_f64()
+    );
+    out
+}
+
+/// Escapes a label value as required by the text exposition format.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
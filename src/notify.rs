@@ -0,0 +1,55 @@
+//! Minimal implementation of systemd's `sd_notify` protocol.
+
+use std::{
+    env,
+    ffi::OsStr,
+    os::{
+        linux::net::SocketAddrExt,
+        unix::{
+            ffi::OsStrExt,
+            net::{SocketAddr, UnixDatagram},
+        },
+    },
+    process,
+    time::Duration,
+};
+
+use tracing::{trace, warn};
+
+/// Sends `state` (e.g. `READY=1`) to the service manager. Does nothing if `NOTIFY_SOCKET` is not
+/// set, i.e. we're not running under systemd with `Type=notify`.
+pub fn notify(state: &str) {
+    let socket_path = match env::var_os("NOTIFY_SOCKET") {
+        Some(v) => v,
+        None => return,
+    };
+    trace!(state, "notifying service manager");
+    if let Err(error) = send(&socket_path, state) {
+        warn!(?error, state, "failed to notify service manager");
+    }
+}
+
+/// The watchdog interval requested by the service manager, if any. `WATCHDOG=1` must be sent more
+/// often than this.
+pub fn watchdog_interval() -> Option<Duration> {
+    if let Some(pid) = env::var_os("WATCHDOG_PID") {
+        // the watchdog is meant for another process
+        if pid.to_str() != Some(&process::id().to_string()) {
+            return None;
+        }
+    }
+    let usec = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    Some(Duration::from_micros(usec))
+}
+
+fn send(socket_path: &OsStr, state: &str) -> std::io::Result<()> {
+    let bytes = socket_path.as_bytes();
+    let addr = match bytes.strip_prefix(b"@") {
+        // a leading @ means an abstract socket
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(socket_path)?,
+    };
+    let socket = UnixDatagram::unbound()?;
+    socket.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(())
+}
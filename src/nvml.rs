@@ -0,0 +1,104 @@
+//! Reads NVIDIA GPU temperatures through NVML, which is loaded at runtime so that whoosh still
+//! starts on machines without the NVIDIA driver.
+
+use std::{
+    ffi::{c_void, CStr},
+    os::raw::{c_char, c_int, c_uint},
+    sync::OnceLock,
+};
+
+use tracing::debug;
+
+use crate::error::Error;
+
+const LIBRARY: &[u8] = b"libnvidia-ml.so.1\0";
+/// `NVML_TEMPERATURE_GPU`, the temperature of the GPU die.
+const TEMPERATURE_GPU: c_int = 0;
+
+type Device = *mut c_void;
+type InitFn = unsafe extern "C" fn() -> c_int;
+type GetHandleByIndexFn = unsafe extern "C" fn(c_uint, *mut Device) -> c_int;
+type GetTemperatureFn = unsafe extern "C" fn(Device, c_int, *mut c_uint) -> c_int;
+
+struct Nvml {
+    get_handle_by_index: GetHandleByIndexFn,
+    get_temperature: GetTemperatureFn,
+}
+
+/// The library is loaded and initialised on first use, then kept for the life of the process.
+static NVML: OnceLock<Result<Nvml, String>> = OnceLock::new();
+
+impl Nvml {
+    fn load() -> Result<Self, String> {
+        // never closed - NVML can't be used after its library is unloaded
+        let handle = unsafe { libc::dlopen(LIBRARY.as_ptr() as *const c_char, libc::RTLD_NOW) };
+        if handle.is_null() {
+            return Err(dl_error());
+        }
+        // names must be nul-terminated
+        let symbol = |name: &[u8]| {
+            let ptr = unsafe { libc::dlsym(handle, name.as_ptr() as *const c_char) };
+            if ptr.is_null() {
+                Err(dl_error())
+            } else {
+                Ok(ptr)
+            }
+        };
+        // safety: these are the signatures documented in nvml.h
+        let (init, get_handle_by_index, get_temperature) = unsafe {
+            (
+                std::mem::transmute::<*mut c_void, InitFn>(symbol(b"nvmlInit_v2\0")?),
+                std::mem::transmute::<*mut c_void, GetHandleByIndexFn>(symbol(
+                    b"nvmlDeviceGetHandleByIndex_v2\0",
+                )?),
+                std::mem::transmute::<*mut c_void, GetTemperatureFn>(symbol(
+                    b"nvmlDeviceGetTemperature\0",
+                )?),
+            )
+        };
+        let code = unsafe { init() };
+        if code != 0 {
+            return Err(format!("nvmlInit_v2 returned {}", code));
+        }
+        debug!("loaded nvml");
+        Ok(Nvml {
+            get_handle_by_index,
+            get_temperature,
+        })
+    }
+}
+
+fn dl_error() -> String {
+    let message = unsafe { libc::dlerror() };
+    if message.is_null() {
+        return "unknown error".to_owned();
+    }
+    unsafe { CStr::from_ptr(message as *const c_char) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Reads the temperature of the GPU at `index`, in millidegrees celsius.
+pub fn read_temp(index: u32) -> Result<i32, Error> {
+    let nvml = NVML
+        .get_or_init(Nvml::load)
+        .as_ref()
+        .map_err(|e| Error::NvmlUnavailable(e.clone()))?;
+    let mut device: Device = std::ptr::null_mut();
+    let code = unsafe { (nvml.get_handle_by_index)(index, &mut device) };
+    if code != 0 {
+        return Err(Error::Nvml {
+            call: "nvmlDeviceGetHandleByIndex_v2",
+            code,
+        });
+    }
+    let mut temp: c_uint = 0;
+    let code = unsafe { (nvml.get_temperature)(device, TEMPERATURE_GPU, &mut temp) };
+    if code != 0 {
+        return Err(Error::Nvml {
+            call: "nvmlDeviceGetTemperature",
+            code,
+        });
+    }
+    Ok(temp as i32 * 1000)
+}
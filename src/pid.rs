@@ -0,0 +1,43 @@
+use std::time::Instant;
+
+use crate::config::PidParams;
+
+/// State carried between polls by a fan in PID mode.
+#[derive(Default)]
+pub struct PidController {
+    /// The accumulated error, in degree-seconds.
+    integral: f64,
+    /// The error at the last update, and when it was calculated.
+    last: Option<(f64, Instant)>,
+}
+
+impl PidController {
+    /// Calculates the fan speed that should bring `temp` millidegrees, read at `now`, towards the
+    /// target.
+    pub fn update(&mut self, params: &PidParams, temp: i32, now: Instant) -> u8 {
+        // positive when too hot, so that positive gains speed the fan up
+        let error = (temp as f64 - params.target_temp as f64) / 1000.0;
+        let (dt, derivative) = match self.last {
+            Some((last_error, last_time)) => {
+                let dt = now.duration_since(last_time).as_secs_f64();
+                if dt > 0.0 {
+                    (dt, (error - last_error) / dt)
+                } else {
+                    (0.0, 0.0)
+                }
+            }
+            None => (0.0, 0.0),
+        };
+        self.last = Some((error, now));
+
+        let integral = self.integral + error * dt;
+        let output = params.kp * error + params.ki * integral + params.kd * derivative;
+        // anti-windup - don't keep integrating while the output is already pinned in the
+        // direction the error is pushing it
+        let saturated = (output > 255.0 && error > 0.0) || (output < 0.0 && error < 0.0);
+        if !saturated {
+            self.integral = integral;
+        }
+        output.round().clamp(0.0, 255.0) as u8
+    }
+}
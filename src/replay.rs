@@ -0,0 +1,155 @@
+//! Records sensor readings during normal operation, and runs a config against them later instead
+//! of real hardware.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    args::Args, config::LabelCache, error::Error, sensor::SensorInput, status::Status,
+    timing::Clock, Config, State,
+};
+
+/// How many polls `Recorder` buffers before flushing, so that a crash loses little.
+const FLUSH_POLLS: usize = 10;
+
+/// One poll's worth of a recording, which is a file with one of these as JSON on each line.
+#[derive(Deserialize)]
+struct Step {
+    /// When the readings were taken, in milliseconds since the unix epoch. Without one, the step
+    /// is taken to be `poll_period` after the last.
+    timestamp: Option<u64>,
+    /// Raw readings, before calibration or smoothing, keyed by sensor name. Sensors missing
+    /// from a step keep their previous reading.
+    sensors: HashMap<String, i32>,
+}
+
+/// What `Recorder` writes for each poll, which is a superset of `Step`.
+#[derive(Serialize)]
+struct Record<'a> {
+    timestamp: u64,
+    sensors: &'a BTreeMap<String, i32>,
+    /// The speed each fan was heading towards, for comparing against a replay.
+    fans: BTreeMap<&'a str, u8>,
+}
+
+/// Appends every poll to a recording.
+pub struct Recorder {
+    out: BufWriter<File>,
+    /// How many polls have been written since the last flush.
+    unflushed: usize,
+}
+
+impl Recorder {
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(Error::file(path))?;
+        Ok(Self {
+            out: BufWriter::new(file),
+            unflushed: 0,
+        })
+    }
+
+    pub fn record(&mut self, status: &Status) -> Result<(), Error> {
+        let record = Record {
+            timestamp: status.timestamp,
+            sensors: &status.raw_sensors,
+            fans: status
+                .fans
+                .iter()
+                .map(|(name, fan)| (name.as_str(), fan.target_speed))
+                .collect(),
+        };
+        serde_json::to_writer(&mut self.out, &record).map_err(Error::Json)?;
+        self.out.write_all(b"\n")?;
+        self.unflushed += 1;
+        if self.unflushed >= FLUSH_POLLS {
+            self.out.flush()?;
+            self.unflushed = 0;
+        }
+        Ok(())
+    }
+}
+
+/// Feeds every step of the recording at `path` through the config, as fast as possible, and
+/// prints what each fan would have been set to as CSV. Nothing is written to real hardware and
+/// `critical_command` is never run. Anything timed, like `rampdown_delay_ms` or pid mode, sees
+/// time pass as the steps' timestamps say it did, but a stopped fan spins up without the
+/// `spinup_ms` wait.
+pub fn replay(args: &Args, path: &Path) -> Result<(), Error> {
+    let mut config = Config::load(&args.config_path)?;
+    config.critical_command = None;
+    let sysfs = Arc::new(config.fake_sysfs()?);
+    let mut state = State::new(
+        config,
+        Arc::clone(&sysfs) as _,
+        false,
+        &mut HashMap::new(),
+        &mut LabelCache::default(),
+    )?;
+
+    let poll_period = Duration::from_millis(state.config.poll_period);
+    let start = Instant::now();
+    let mut first_timestamp = None;
+    let mut last_time: Option<Instant> = None;
+
+    let recording = BufReader::new(File::open(path).map_err(Error::file(path))?);
+    let mut out = std::io::stdout().lock();
+    writeln!(out, "timestamp,fan,input_temp_c,target_pwm,pwm")?;
+    for (i, line) in recording.lines().enumerate() {
+        let line = line.map_err(Error::file(path))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let step: Step = serde_json::from_str(&line)
+            .map_err(|error| Error::InvalidRecording { line: i + 1, error })?;
+        for (name, reading) in step.sensors.iter() {
+            if let Some(SensorInput::File(path)) = state.sensor_inputs.get(name) {
+                sysfs.insert(path.clone(), format!("{}\n", reading));
+            }
+        }
+
+        // time starts at the first step, and never goes backwards
+        let time = match step.timestamp {
+            Some(timestamp) => {
+                let first = *first_timestamp.get_or_insert(timestamp);
+                start + Duration::from_millis(timestamp.saturating_sub(first))
+            }
+            None => last_time.map_or(start, |last_time| last_time + poll_period),
+        };
+        let time = last_time.map_or(time, |last_time| last_time.max(time));
+        last_time = Some(time);
+        state.clock = Clock::Simulated(time);
+        let timestamp = step.timestamp.unwrap_or(0);
+
+        let mut status = Status {
+            timestamp,
+            profile: state.profile.clone(),
+            ..Status::default()
+        };
+        state.poll(&mut status)?;
+        for (name, fan) in status.fans.iter() {
+            let pwm = state.fans[name].get_speed()?;
+            writeln!(
+                out,
+                "{},{},{},{},{}",
+                timestamp,
+                name,
+                fan.input_temp as f64 / 1000.0,
+                fan.target_speed,
+                pwm
+            )?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,103 @@
+use std::{
+    collections::VecDeque,
+    fmt::{self, Display, Formatter},
+    path::PathBuf,
+};
+
+use crate::{config::Sensor, error::Error, mean, sysfs::Sysfs};
+
+/// Where a sensor's readings come from.
+pub enum SensorInput {
+    /// A file containing a single number, usually an integer.
+    File(PathBuf),
+    /// The index of an NVIDIA GPU, read through NVML.
+    #[cfg(feature = "nvml")]
+    Nvml(u32),
+}
+
+impl SensorInput {
+    /// Takes a raw reading, which is in millidegrees celsius unless the sensor is scaled.
+    pub fn read(&self, sysfs: &dyn Sysfs) -> Result<i32, Error> {
+        match self {
+            SensorInput::File(path) => {
+                let reading = sysfs.read_to_string(path).map_err(Error::file(path))?;
+                let reading = reading.trim();
+                reading.parse().or_else(|error| {
+                    // a few drivers give fractions of a millidegree
+                    match reading.parse::<f64>() {
+                        Ok(v) if v.round() >= i32::MIN as f64 && v.round() <= i32::MAX as f64 => {
+                            Ok(v.round() as i32)
+                        }
+                        _ => Err(Error::InvalidReading {
+                            path: path.display().to_string(),
+                            value: reading.to_owned(),
+                            error,
+                        }),
+                    }
+                })
+            }
+            #[cfg(feature = "nvml")]
+            SensorInput::Nvml(index) => crate::nvml::read_temp(*index),
+        }
+    }
+}
+
+impl Display for SensorInput {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            SensorInput::File(path) => write!(f, "{}", path.display()),
+            #[cfg(feature = "nvml")]
+            SensorInput::Nvml(index) => write!(f, "nvml gpu {}", index),
+        }
+    }
+}
+
+/// Per-sensor state carried between polls, used to smooth readings.
+#[derive(Default)]
+pub struct SensorHistory {
+    /// The most recent raw readings, oldest first, if the sensor has a `window`.
+    window: VecDeque<i32>,
+    /// The exponential moving average, if the sensor has `smoothing`.
+    smoothed: Option<f64>,
+    /// The last value returned by `update`.
+    last_temp: Option<i32>,
+}
+
+impl SensorHistory {
+    /// Records a new calibrated reading and returns the value to use after the sensor's
+    /// configured moving average and smoothing are applied, in that order. Implausible readings
+    /// are ignored and `None` is returned.
+    pub fn update(&mut self, sensor: &Sensor, raw_temp: i32) -> Option<i32> {
+        if !sensor.is_plausible(raw_temp) {
+            return None;
+        }
+
+        let mut temp = raw_temp;
+
+        if let Some(window) = sensor.window {
+            if self.window.len() == window {
+                self.window.pop_front();
+            }
+            self.window.push_back(raw_temp);
+            // until the window fills, average whatever we have
+            temp = mean(self.window.make_contiguous());
+        }
+
+        if let Some(alpha) = sensor.smoothing {
+            let smoothed = match self.smoothed {
+                Some(last) => alpha * temp as f64 + (1.0 - alpha) * last,
+                None => temp as f64,
+            };
+            self.smoothed = Some(smoothed);
+            temp = smoothed.round() as i32;
+        }
+
+        self.last_temp = Some(temp);
+        Some(temp)
+    }
+
+    /// The last value returned by `update`, if any.
+    pub fn last_temp(&self) -> Option<i32> {
+        self.last_temp
+    }
+}
@@ -0,0 +1,156 @@
+//! Plain-text status over a unix socket. A client may also send `profile NAME` first to switch
+//! profiles, where `default` means the base config.
+
+use std::{
+    fmt::Write as _,
+    fs::{remove_file, symlink_metadata},
+    io::{BufRead, BufReader, Write},
+    os::unix::{
+        fs::FileTypeExt,
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+};
+
+use tracing::{debug, info, warn};
+
+use crate::{
+    error::Error,
+    status::{ProfileRequest, SharedStatus, Status},
+};
+
+/// How long to wait for a client to send a command before just replying with the status.
+const COMMAND_TIMEOUT_MS: u64 = 100;
+
+pub struct StatusSocket {
+    path: PathBuf,
+}
+
+impl StatusSocket {
+    /// Listens on `path` and replies to each connection with the latest `status` on a background
+    /// thread, passing on any profile switch to `profile_request`. A socket already at `path`, e.g. left
+    /// behind by a crash, is replaced, but any other file is left alone.
+    pub fn start(
+        path: &Path,
+        status: SharedStatus,
+        profile_request: ProfileRequest,
+    ) -> Result<Self, Error> {
+        if let Ok(metadata) = symlink_metadata(path) {
+            if !metadata.file_type().is_socket() {
+                return Err(Error::NotASocket(path.display().to_string()));
+            }
+            remove_file(path).map_err(Error::file(path))?;
+        }
+        let listener = UnixListener::bind(path)?;
+        info!(path = ?path, "serving status");
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let res = stream.and_then(|stream| handle(stream, &status, &profile_request));
+                if let Err(error) = res {
+                    debug!(?error, "failed to serve status request");
+                }
+            }
+        });
+        Ok(Self {
+            path: path.to_owned(),
+        })
+    }
+
+    /// Starts listening if `path` is set and the socket isn't already open. The socket lives as
+    /// long as the process, so a changed path only takes effect on restart.
+    pub fn ensure_started(
+        socket: &mut Option<Self>,
+        path: Option<&Path>,
+        status: &SharedStatus,
+        profile_request: &ProfileRequest,
+    ) -> Result<(), Error> {
+        match (socket.as_ref(), path) {
+            (None, Some(path)) => {
+                *socket = Some(Self::start(
+                    path,
+                    SharedStatus::clone(status),
+                    ProfileRequest::clone(profile_request),
+                )?)
+            }
+            (Some(socket), Some(path)) if socket.path != path => {
+                warn!(old = ?socket.path, new = ?path, "status_socket changed - restart to apply");
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+impl Drop for StatusSocket {
+    fn drop(&mut self) {
+        if let Err(error) = remove_file(&self.path) {
+            warn!(path = ?self.path, ?error, "failed to remove status socket");
+        }
+    }
+}
+
+fn handle(
+    mut stream: UnixStream,
+    status: &SharedStatus,
+    profile_request: &ProfileRequest,
+) -> std::io::Result<()> {
+    // clients that only want the status won't send anything, so don't wait long
+    stream.set_read_timeout(Some(Duration::from_millis(COMMAND_TIMEOUT_MS)))?;
+    let mut command = String::new();
+    if BufReader::new(&stream).read_line(&mut command).is_ok() {
+        if let Some(profile) = command.trim().strip_prefix("profile ") {
+            debug!(profile, "profile switch requested");
+            *profile_request.lock().unwrap() = Some(profile.trim().to_owned());
+        }
+    }
+    let reply = render(&status.lock().unwrap());
+    stream.write_all(reply.as_bytes())
+}
+
+fn render(status: &Status) -> String {
+    let mut out = String::new();
+    if let Some(profile) = &status.profile {
+        let _ = writeln!(out, "profile {}", profile);
+    }
+    let unit = status.display_unit;
+    for (name, temp) in status.sensors.iter() {
+        let _ = writeln!(out, "sensor {} {}", name, unit.show(*temp));
+    }
+    for (name, composite) in status.composites.iter() {
+        let _ = write!(out, "composite {} {}", name, unit.show(composite.temp));
+        for (input, temp) in composite.inputs.iter() {
+            let _ = write!(out, " {}={}", input, unit.show(*temp));
+        }
+        out.push('\n');
+    }
+    for (name, fan) in status.fans.iter() {
+        let _ = write!(
+            out,
+            "fan {} current={} target={}",
+            name, fan.current_speed, fan.target_speed
+        );
+        if let Some(rpm) = fan.rpm {
+            let _ = write!(out, " rpm={}", rpm);
+        }
+        out.push('\n');
+    }
+    let timing = &status.timing;
+    for (phase, stats) in [
+        ("sensors", &timing.sensors),
+        ("composites", &timing.composites),
+        ("fans", &timing.fans),
+        ("total", &timing.total),
+    ] {
+        let _ = writeln!(
+            out,
+            "timing {} min={} mean={} max={}",
+            phase,
+            stats.min.as_secs_f64(),
+            stats.mean.as_secs_f64(),
+            stats.max.as_secs_f64()
+        );
+    }
+    out
+}
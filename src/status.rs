@@ -0,0 +1,86 @@
+use std::{
+    collections::BTreeMap,
+    fs::{rename, write},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde::{Serialize, Serializer};
+
+use crate::{
+    config::TempUnit,
+    error::Error,
+    timing::{PollTimes, TimingStats},
+};
+
+/// A snapshot of what the control loop did in its most recent poll.
+#[derive(Clone, Default, Serialize)]
+pub struct Status {
+    /// When the poll started, in milliseconds since the unix epoch.
+    pub timestamp: u64,
+    /// The active profile, if any.
+    pub profile: Option<String>,
+    /// Sensor readings, in millidegrees celsius.
+    pub sensors: BTreeMap<String, i32>,
+    /// Sensor readings as they were read, before calibration or smoothing.
+    pub raw_sensors: BTreeMap<String, i32>,
+    pub composites: BTreeMap<String, CompositeStatus>,
+    pub fans: BTreeMap<String, FanStatus>,
+    /// How long the poll took, not including the sleep afterwards.
+    #[serde(serialize_with = "serialize_secs")]
+    pub poll_duration: Duration,
+    /// How long each phase of the poll took.
+    pub poll_times: PollTimes,
+    /// Poll times over the last few polls.
+    pub timing: TimingStats,
+    /// How temperatures should be shown to people, who don't read the JSON.
+    #[serde(skip)]
+    pub display_unit: TempUnit,
+}
+
+impl Status {
+    /// Writes the status as JSON, replacing `path` atomically so readers never see a partial
+    /// file.
+    pub fn write_json(&self, path: &Path) -> Result<(), Error> {
+        let json = serde_json::to_vec(self).map_err(Error::Json)?;
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        write(&tmp_path, json)?;
+        rename(&tmp_path, path)?;
+        Ok(())
+    }
+}
+
+pub fn serialize_secs<S: Serializer>(
+    duration: &Duration,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64())
+}
+
+#[derive(Clone, Serialize)]
+pub struct CompositeStatus {
+    /// The composite's value, in millidegrees celsius.
+    pub temp: i32,
+    /// The values of the inputs it was calculated from, leaving out any that weren't available.
+    pub inputs: BTreeMap<String, i32>,
+}
+
+#[derive(Clone, Serialize)]
+pub struct FanStatus {
+    /// The temperature the fan's speed was calculated from, in millidegrees celsius.
+    pub input_temp: i32,
+    /// The PWM value read from the fan at the start of the poll.
+    pub current_speed: u8,
+    /// The PWM value the fan is heading towards.
+    pub target_speed: u8,
+    /// The speed measured by the fan's tachometer, if it has one.
+    pub rpm: Option<u32>,
+}
+
+/// The latest status, published by the control loop for other threads to read.
+pub type SharedStatus = Arc<Mutex<Status>>;
+
+/// A profile switch asked for over the status socket, waiting for the control loop to pick it up.
+pub type ProfileRequest = Arc<Mutex<Option<String>>>;
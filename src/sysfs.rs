@@ -0,0 +1,135 @@
+//! Access to the files in sysfs, behind a trait so that a fake hwmon tree can stand in for real
+//! hardware.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    io::{Error as IoError, ErrorKind, Result as IoResult},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+pub trait Sysfs: Send + Sync {
+    fn read_to_string(&self, path: &Path) -> IoResult<String>;
+    /// Writes to an existing file. Files in sysfs can't be created.
+    fn write(&self, path: &Path, contents: &str) -> IoResult<()>;
+    /// The paths of everything in the directory at `path`.
+    fn read_dir(&self, path: &Path) -> IoResult<Vec<PathBuf>>;
+    fn canonicalize(&self, path: &Path) -> IoResult<PathBuf>;
+    fn exists(&self, path: &Path) -> bool;
+}
+
+/// A `Sysfs` shared between everything that reads or writes hardware.
+pub type SharedSysfs = Arc<dyn Sysfs>;
+
+/// The real filesystem.
+pub struct RealSysfs;
+
+impl Sysfs for RealSysfs {
+    fn read_to_string(&self, path: &Path) -> IoResult<String> {
+        fs::read_to_string(path)
+    }
+
+    fn write(&self, path: &Path, contents: &str) -> IoResult<()> {
+        fs::write(path, contents)
+    }
+
+    fn read_dir(&self, path: &Path) -> IoResult<Vec<PathBuf>> {
+        fs::read_dir(path)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect()
+    }
+
+    fn canonicalize(&self, path: &Path) -> IoResult<PathBuf> {
+        fs::canonicalize(path)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+}
+
+/// An in-memory tree of files and symlinks. Directories exist wherever something is inside them.
+#[derive(Default)]
+pub struct FakeSysfs {
+    files: Mutex<BTreeMap<PathBuf, String>>,
+    links: Mutex<BTreeMap<PathBuf, PathBuf>>,
+}
+
+impl FakeSysfs {
+    /// Creates the file at `path`, or replaces its contents.
+    pub fn insert(&self, path: impl Into<PathBuf>, contents: impl Into<String>) {
+        self.files
+            .lock()
+            .unwrap()
+            .insert(path.into(), contents.into());
+    }
+
+    /// Makes `path` a symlink to `target`, which is what `canonicalize` resolves it to.
+    pub fn link(&self, path: impl Into<PathBuf>, target: impl Into<PathBuf>) {
+        self.links
+            .lock()
+            .unwrap()
+            .insert(path.into(), target.into());
+    }
+
+    /// The contents of the file at `path`, e.g. to check what was written to it.
+    pub fn get(&self, path: impl AsRef<Path>) -> Option<String> {
+        self.files.lock().unwrap().get(path.as_ref()).cloned()
+    }
+}
+
+fn not_found(path: &Path) -> IoError {
+    IoError::new(ErrorKind::NotFound, format!("{} not found", path.display()))
+}
+
+impl Sysfs for FakeSysfs {
+    fn read_to_string(&self, path: &Path) -> IoResult<String> {
+        self.get(path).ok_or_else(|| not_found(path))
+    }
+
+    fn write(&self, path: &Path, contents: &str) -> IoResult<()> {
+        match self.files.lock().unwrap().get_mut(path) {
+            Some(file) => {
+                *file = contents.to_owned();
+                Ok(())
+            }
+            None => Err(not_found(path)),
+        }
+    }
+
+    fn read_dir(&self, path: &Path) -> IoResult<Vec<PathBuf>> {
+        let files = self.files.lock().unwrap();
+        let links = self.links.lock().unwrap();
+        // the child of `path` that each file or link is in, or is
+        let children: BTreeSet<PathBuf> = files
+            .keys()
+            .chain(links.keys())
+            .filter_map(|entry| {
+                let rest = entry.strip_prefix(path).ok()?;
+                Some(path.join(rest.components().next()?))
+            })
+            .collect();
+        if children.is_empty() {
+            return Err(not_found(path));
+        }
+        Ok(children.into_iter().collect())
+    }
+
+    fn canonicalize(&self, path: &Path) -> IoResult<PathBuf> {
+        if let Some(target) = self.links.lock().unwrap().get(path) {
+            return Ok(target.clone());
+        }
+        if self.exists(path) {
+            Ok(path.to_owned())
+        } else {
+            Err(not_found(path))
+        }
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        let in_tree = |entry: &PathBuf| entry.starts_with(path);
+        self.files.lock().unwrap().keys().any(in_tree)
+            || self.links.lock().unwrap().keys().any(in_tree)
+    }
+}
@@ -0,0 +1,113 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use crate::status::serialize_secs;
+
+/// How many of the most recent polls timing statistics cover.
+pub const WINDOW: usize = 60;
+
+/// Where the control loop gets the time from, for anything that depends on how long it has been
+/// since an earlier poll.
+#[derive(Clone, Copy, Default)]
+pub enum Clock {
+    #[default]
+    Real,
+    /// A time that only moves when it's set, for running through a recording at the pace it was
+    /// recorded at.
+    Simulated(Instant),
+}
+
+impl Clock {
+    pub fn now(self) -> Instant {
+        match self {
+            Clock::Real => Instant::now(),
+            Clock::Simulated(now) => now,
+        }
+    }
+}
+
+/// How long each phase of a poll took.
+#[derive(Clone, Copy, Default, Serialize)]
+pub struct PollTimes {
+    #[serde(serialize_with = "serialize_secs")]
+    pub sensors: Duration,
+    #[serde(serialize_with = "serialize_secs")]
+    pub composites: Duration,
+    #[serde(serialize_with = "serialize_secs")]
+    pub fans: Duration,
+}
+
+#[derive(Clone, Copy, Default, Serialize)]
+pub struct Stats {
+    #[serde(serialize_with = "serialize_secs")]
+    pub min: Duration,
+    #[serde(serialize_with = "serialize_secs")]
+    pub mean: Duration,
+    #[serde(serialize_with = "serialize_secs")]
+    pub max: Duration,
+}
+
+impl Stats {
+    fn of(durations: impl Iterator<Item = Duration> + Clone) -> Self {
+        let count = durations.clone().count() as u32;
+        if count == 0 {
+            return Self::default();
+        }
+        Self {
+            min: durations.clone().min().unwrap(),
+            mean: durations.clone().sum::<Duration>() / count,
+            max: durations.max().unwrap(),
+        }
+    }
+}
+
+/// Statistics for each phase of a poll over the last `WINDOW` polls.
+#[derive(Clone, Copy, Default, Serialize)]
+pub struct TimingStats {
+    pub sensors: Stats,
+    pub composites: Stats,
+    pub fans: Stats,
+    /// The whole poll, not including the sleep afterwards.
+    pub total: Stats,
+}
+
+/// Keeps the times of the last `WINDOW` polls.
+#[derive(Default)]
+pub struct PollTimer {
+    /// Each poll's total duration and the times of its phases.
+    polls: VecDeque<(Duration, PollTimes)>,
+    /// How many polls have been added since the window was last full of new ones.
+    since_window: usize,
+}
+
+impl PollTimer {
+    /// Adds a poll's times, returning true every `WINDOW` polls so the caller can log the
+    /// statistics.
+    pub fn add(&mut self, total: Duration, times: PollTimes) -> bool {
+        if self.polls.len() == WINDOW {
+            self.polls.pop_front();
+        }
+        self.polls.push_back((total, times));
+        self.since_window += 1;
+        if self.since_window == WINDOW {
+            self.since_window = 0;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn stats(&self) -> TimingStats {
+        let polls = self.polls.iter();
+        TimingStats {
+            sensors: Stats::of(polls.clone().map(|(_, times)| times.sensors)),
+            composites: Stats::of(polls.clone().map(|(_, times)| times.composites)),
+            fans: Stats::of(polls.clone().map(|(_, times)| times.fans)),
+            total: Stats::of(polls.map(|(total, _)| *total)),
+        }
+    }
+}